@@ -0,0 +1,124 @@
+/* Definitions shared by programs running on badvm. */
+#ifndef BADVM_H
+#define BADVM_H
+
+/* Pixel formats. Colors are 0xAARRGGBB in FORMAT_ARGB32, palette indices in
+   FORMAT_INDEXED8, and 0 or 1 in FORMAT_MONO1; each keeps only the low bits it needs. */
+#define FORMAT_ARGB32 0
+#define FORMAT_INDEXED8 1
+#define FORMAT_MONO1 2
+
+/* Bytes written from FRAMEBUFFER on show up on the display, row by row. The display
+   starts out 64 x 64 in FORMAT_INDEXED8, where the palette is a ramp of grey,
+   unless `vm` is told otherwise. Accessing bytes past the ones the mode uses
+   stops the VM. */
+#define FRAMEBUFFER 0x8000
+/* The current mode, which programs can read but not write. */
+#define DISPLAY 0x48000
+#define WIDTH (*(int *)DISPLAY)
+#define HEIGHT (*(int *)(DISPLAY + 4))
+#define FORMAT (*(int *)(DISPLAY + 8))
+/* The 256 colors FORMAT_INDEXED8 pixels stand for, as 0xRRGGBB ints. They are
+   looked up every time the display is shown, so changing them recolors the screen. */
+#define PALETTE 0x48100
+
+/* Keyboard and mouse, which programs can read but not write. The mouse position
+   is in display pixels and BUTTONS has bit 0 set while the left button is down,
+   bit 1 for the right one and bit 2 for the middle one. */
+#define INPUT 0x48800
+#define MOUSE_X (*(int *)INPUT)
+#define MOUSE_Y (*(int *)(INPUT + 4))
+#define BUTTONS (*(int *)(INPUT + 8))
+/* Key and button events waiting for next_event. */
+#define EVENTS (*(int *)(INPUT + 12))
+/* The event next_event took: its type, key code or button, and the mouse position. */
+#define EVENT_TYPE (*(int *)(INPUT + 16))
+#define EVENT_CODE (*(int *)(INPUT + 20))
+#define EVENT_X (*(int *)(INPUT + 24))
+#define EVENT_Y (*(int *)(INPUT + 28))
+/* 1 while the key is down. Letters and digits are their ASCII code as capitals,
+   so key_down('W') is the W key. */
+#define key_down(key) (((unsigned char *)(INPUT + 32))[key])
+
+#define EVENT_NONE 0
+#define EVENT_KEY_DOWN 1
+#define EVENT_KEY_UP 2
+#define EVENT_BUTTON_DOWN 3
+#define EVENT_BUTTON_UP 4
+
+#define BUTTON_LEFT 0
+#define BUTTON_RIGHT 1
+#define BUTTON_MIDDLE 2
+
+#define KEY_BACKSPACE 8
+#define KEY_TAB 9
+#define KEY_ENTER 10
+#define KEY_ESCAPE 27
+#define KEY_SPACE 32
+#define KEY_LEFT 128
+#define KEY_RIGHT 129
+#define KEY_UP 130
+#define KEY_DOWN 131
+#define KEY_SHIFT 132
+#define KEY_CTRL 133
+#define KEY_ALT 134
+
+/* Time, which programs can read but not write. TICKS counts the instructions run
+   and MILLIS the milliseconds since the program started; TIME is milliseconds since
+   1970. With `vm --fixed-clock N` time passes at N instructions to the millisecond
+   and starts at 1970, so every run sees the same times. */
+#define CLOCK 0x48c00
+#define TICKS (*(long *)CLOCK)
+#define MILLIS (*(long *)(CLOCK + 8))
+#define TIME (*(long *)(CLOCK + 16))
+/* How many times the timer has gone off since set_timer. */
+#define TIMER (*(int *)(CLOCK + 24))
+
+#define TIMER_MILLIS 0
+#define TIMER_INSTRUCTIONS 1
+
+#define rgb(r, g, b) ((r) * 0x10000 + (g) * 0x100 + (b))
+/* Black and white work in every format. */
+#define BLACK 0
+#define WHITE -1
+#define RED 0xff0000
+#define GREEN 0x00ff00
+#define BLUE 0x0000ff
+
+/* Sets pixel (x, y); the VM stops if it is off the display. */
+void put_pixel(int x, int y, int color);
+/* Sets pixel y * WIDTH + x. */
+void put_pixel_index(int index, int color);
+/* Marks the frame as finished. Once a program finishes frames, the window only
+   shows finished ones, and `vm --capture-frames` saves each of them. */
+void end_frame(void);
+/* Finishes the frame like end_frame, then waits for the window's next frame at
+   60 per second. Without a window it returns straight away. */
+void present(void);
+/* Switches to another resolution and pixel format, clearing the display. The
+   framebuffer holds 0x40000 bytes, so 256 x 256 is the most FORMAT_ARGB32 allows. */
+void set_display(int width, int height, int format);
+/* Sets palette entry index (0 to 255) to color, the same as writing PALETTE. */
+void set_palette(int index, int color);
+
+/* These draw whatever part of the shape is on the display and skip the rest. */
+void clear_screen(int color);
+void fill_rect(int x, int y, int width, int height, int color);
+void draw_line(int x0, int y0, int x1, int y1, int color);
+/* Copies a width x height sprite, row by row, leaving out pixels equal to key.
+   Sprite pixels are a byte each, except in FORMAT_ARGB32 where they are ints. */
+void blit(void *sprite, int x, int y, int width, int height, int key);
+/* Draws text in the built-in 3x5 font, 4 pixels per character and 6 per line.
+   Lowercase is drawn as capitals and a newline goes back to x on the next line. */
+void draw_text(char *text, int x, int y, int color);
+
+/* Takes the oldest key or button event off the queue into the EVENT registers and
+   returns its type, or EVENT_NONE if there is none. Events that come in while 256
+   are waiting are dropped, but key_down and BUTTONS still follow them. */
+int next_event(void);
+
+/* Starts TIMER counting up from 0 every period milliseconds or instructions, as
+   unit says. A period of 0 or less stops it. */
+void set_timer(int period, int unit);
+
+#endif
@@ -0,0 +1,158 @@
+/* The runtime library. Programs get whichever of these functions they use
+   linked in, unless they define a function with the same name themselves. */
+#include "badvm.h"
+#include "string.h"
+#include "stdlib.h"
+
+void put_pixel(int x, int y, int color) {
+    __interrupt(0x01, x, y, color);
+}
+
+void put_pixel_index(int index, int color) {
+    __interrupt(0x00, color, index);
+}
+
+void end_frame(void) {
+    __interrupt(0x02);
+}
+
+void present(void) {
+    __interrupt(0x0b);
+}
+
+void set_display(int width, int height, int format) {
+    __interrupt(0x03, width, height, format);
+}
+
+void set_palette(int index, int color) {
+    __interrupt(0x04, index, color);
+}
+
+void clear_screen(int color) {
+    __interrupt(0x05, color);
+}
+
+void fill_rect(int x, int y, int width, int height, int color) {
+    __interrupt(0x06, x, y, width, height, color);
+}
+
+void draw_line(int x0, int y0, int x1, int y1, int color) {
+    __interrupt(0x07, x0, y0, x1, y1, color);
+}
+
+void blit(void *sprite, int x, int y, int width, int height, int key) {
+    __interrupt(0x08, sprite, x, y, width, height, key);
+}
+
+void draw_text(char *text, int x, int y, int color) {
+    __interrupt(0x09, text, x, y, color);
+}
+
+int next_event(void) {
+    __interrupt(0x0a);
+    return EVENT_TYPE;
+}
+
+void set_timer(int period, int unit) {
+    __interrupt(0x0c, period, unit);
+}
+
+void *memset(void *dst, int value, unsigned long count) {
+    unsigned char *p = dst;
+    while (count != 0) {
+        *p = value;
+        p = p + 1;
+        count = count - 1;
+    }
+    return dst;
+}
+
+void *memcpy(void *dst, void *src, unsigned long count) {
+    unsigned char *to = dst;
+    unsigned char *from = src;
+    while (count != 0) {
+        *to = *from;
+        to = to + 1;
+        from = from + 1;
+        count = count - 1;
+    }
+    return dst;
+}
+
+unsigned long strlen(char *s) {
+    unsigned long n = 0;
+    while (s[n] != 0) {
+        n = n + 1;
+    }
+    return n;
+}
+
+int abs(int n) {
+    if (n < 0) {
+        return -n;
+    }
+    return n;
+}
+
+int min(int a, int b) {
+    if (a < b) {
+        return a;
+    }
+    return b;
+}
+
+int max(int a, int b) {
+    if (a > b) {
+        return a;
+    }
+    return b;
+}
+
+/* A 64-bit linear congruential generator; rand returns the top 31 bits. */
+unsigned long __rand_state = 1;
+
+int rand(void) {
+    __rand_state = __rand_state * 6364136223846793005 + 1442695040888963407;
+    return __rand_state / 8589934592;
+}
+
+void srand(unsigned int seed) {
+    __rand_state = seed;
+}
+
+char *itoa(int value, char *buf, int base) {
+    char digits[33];
+    int count = 0;
+    char *out = buf;
+    /* Widened first so that negating the smallest int does not overflow. */
+    long n = value;
+    if (base < 2) {
+        *buf = 0;
+        return buf;
+    }
+    if (base > 36) {
+        *buf = 0;
+        return buf;
+    }
+    if (n < 0) {
+        *out = '-';
+        out = out + 1;
+        n = -n;
+    }
+    if (n == 0) {
+        digits[0] = '0';
+        count = 1;
+    }
+    while (n != 0) {
+        digits[count] = "0123456789abcdefghijklmnopqrstuvwxyz"[n - n / base * base];
+        n = n / base;
+        count = count + 1;
+    }
+    while (count != 0) {
+        count = count - 1;
+        *out = digits[count];
+        out = out + 1;
+    }
+    *out = 0;
+    return buf;
+}
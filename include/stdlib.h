@@ -0,0 +1,21 @@
+/* Integer helpers and random numbers from the runtime library. */
+#ifndef STDLIB_H
+#define STDLIB_H
+
+#define NULL 0
+#define RAND_MAX 2147483647
+
+int abs(int n);
+int min(int a, int b);
+int max(int a, int b);
+
+/* Returns a pseudo-random number from 0 to RAND_MAX. The sequence is the same on every run. */
+int rand(void);
+/* Starts a new sequence; srand(1) gives the one rand starts with. */
+void srand(unsigned int seed);
+
+/* Writes value in base 2 to 36 to buf with a terminating zero, returning buf.
+   buf needs room for 34 bytes in the worst case. */
+char *itoa(int value, char *buf, int base);
+
+#endif
@@ -0,0 +1,14 @@
+/* Memory and string functions from the runtime library. */
+#ifndef STRING_H
+#define STRING_H
+
+#define NULL 0
+
+/* Sets count bytes at dst to value, returning dst. */
+void *memset(void *dst, int value, unsigned long count);
+/* Copies count bytes from src to dst, which must not overlap, returning dst. */
+void *memcpy(void *dst, void *src, unsigned long count);
+/* The number of bytes before the terminating zero. */
+unsigned long strlen(char *s);
+
+#endif
@@ -1,71 +1,230 @@
 use crate::Instruction;
-pub fn assemble(str: String) -> Vec<u8> {
-    let mut out: Vec<u8> = Vec::new();
+use std::collections::HashMap;
+
+pub enum Operand {
+    Value(i64),
+    Label(String),
+    // `%name`, the address of a variable, for inline assembly in C.
+    Variable(String),
+}
+pub enum Item {
+    Label(String),
+    Instr(Instruction, Option<Operand>),
+    Bytes(Vec<u8>),
+    Quad(Operand),
+    Align(usize),
+    // Everything after `.bss` only reserves memory past the end of the image.
+    Bss,
+    Space(usize),
+}
 
-    for i in str.lines() {
-        if i.trim().is_empty() || i.starts_with(";") {
-            continue;
+fn number(arg: &str) -> Option<i64> {
+    match arg.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok(),
+    }
+}
+fn operand(arg: &str) -> Operand {
+    if let Some(name) = arg.strip_prefix('%') {
+        return Operand::Variable(name.to_string());
+    }
+    match number(arg) {
+        Some(value) => Operand::Value(value),
+        None => Operand::Label(arg.to_string()),
+    }
+}
+
+pub fn parse_line(line: &str) -> Result<Vec<Item>, String> {
+    // Comments run from `;` to the end of the line.
+    let line = line.split(';').next().unwrap().trim();
+    let mut items = Vec::new();
+    let line = match line.split_once(':') {
+        Some((label, rest)) => {
+            items.push(Item::Label(label.trim().to_string()));
+            rest.trim()
+        }
+        None => line,
+    };
+    if line.is_empty() {
+        return Ok(items);
+    }
+    let (op, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let args: Vec<&str> = rest
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|arg| !arg.is_empty())
+        .collect();
+    let count = |expected: usize| {
+        if args.len() != expected {
+            return Err(format!("{} takes {} operands", op, expected));
+        }
+        Ok(())
+    };
+    let size = |arg: &str| match number(arg) {
+        Some(size) if size >= 0 => Ok(size as usize),
+        _ => Err(format!("invalid size {}", arg)),
+    };
+    items.push(match op {
+        ".byte" => Item::Bytes(
+            args.iter()
+                .map(|arg| match number(arg) {
+                    Some(byte) if (-128..=255).contains(&byte) => Ok(byte as u8),
+                    _ => Err(format!("invalid byte {}", arg)),
+                })
+                .collect::<Result<_, _>>()?,
+        ),
+        ".quad" => {
+            count(1)?;
+            Item::Quad(operand(args[0]))
+        }
+        ".align" => {
+            count(1)?;
+            Item::Align(size(args[0])?.max(1))
         }
-        let mut iter = i.split_whitespace();
-        let op = iter.next().unwrap();
-        let arg = iter.next();
-        match op {
-            "nop" => out.push(Instruction::Nop as u8),
-            "hlt" => out.push(Instruction::Halt as u8),
-            "push" => {
-                let unwrapped_arg = arg.unwrap();
-                if unwrapped_arg.starts_with("0x") {
-                    let arg =
-                        i64::from_str_radix(unwrapped_arg.trim_start_matches("0x"), 16).unwrap();
-                    out.push(Instruction::Push as u8);
-                    out.append(&mut arg.to_le_bytes().to_vec());
-                } else {
-                    let arg = i64::from_str_radix(unwrapped_arg, 10).unwrap();
-                    out.push(Instruction::Push as u8);
-                    out.append(&mut arg.to_le_bytes().to_vec());
+        ".bss" => {
+            count(0)?;
+            Item::Bss
+        }
+        ".space" => {
+            count(1)?;
+            Item::Space(size(args[0])?)
+        }
+        _ => {
+            let instr = match Instruction::from_mnemonic(op) {
+                Some(instr) => instr,
+                None => return Err(format!("unknown instruction {}", op)),
+            };
+            if instr.operand_size() == 0 {
+                count(0)?;
+                Item::Instr(instr, None)
+            } else {
+                count(1)?;
+                let operand = operand(args[0]);
+                if let Operand::Value(value) = operand {
+                    if instr.operand_size() == 1 && !(0..=255).contains(&value) {
+                        return Err(format!("{} needs an operand from 0 to 255", op));
+                    }
                 }
+                Item::Instr(instr, Some(operand))
             }
-            "pop" => out.push(Instruction::Pop as u8),
-            "add" => out.push(Instruction::Add as u8),
-            "mul" => out.push(Instruction::Mul as u8),
-            "sub" => out.push(Instruction::Sub as u8),
-            "div" => out.push(Instruction::Div as u8),
-            "jmp" => out.push(Instruction::Jump as u8),
-            "loadu8" => out.push(Instruction::LoadU8 as u8),
-            "storeu8" => out.push(Instruction::StoreU8 as u8),
-            "swap" => out.push(Instruction::Swap as u8),
-            "dup" => out.push(Instruction::Dupe as u8),
-            "dupp" => {
-                let unwrapped_arg = arg.unwrap();
-                if unwrapped_arg.starts_with("0x") {
-                    let arg =
-                        i64::from_str_radix(unwrapped_arg.trim_start_matches("0x"), 16).unwrap();
-                    out.push(Instruction::DupeAt as u8);
-                    out.append(&mut arg.to_le_bytes().to_vec());
-                } else {
-                    let arg = i64::from_str_radix(unwrapped_arg, 10).unwrap();
-                    out.push(Instruction::DupeAt as u8);
-                    out.append(&mut arg.to_le_bytes().to_vec());
+        }
+    });
+    Ok(items)
+}
+
+// Labels can be used before they are defined, so the addresses are worked out first.
+pub fn assemble(str: String) -> Vec<u8> {
+    let mut items = Vec::new();
+    for (i, line) in str.lines().enumerate() {
+        match parse_line(line) {
+            Ok(line) => items.extend(line.into_iter().map(|item| (i + 1, item))),
+            Err(message) => panic!("line {}: {}", i + 1, message),
+        }
+    }
+    let mut labels = HashMap::new();
+    let mut address: usize = 0;
+    let mut bss = false;
+    for (n, item) in &items {
+        let size = match item {
+            Item::Label(label) => {
+                if labels.insert(label.clone(), address).is_some() {
+                    panic!("line {}: label {} defined twice", n, label);
                 }
+                continue;
+            }
+            Item::Align(align) => address.div_ceil(*align) * align - address,
+            Item::Bss => {
+                bss = true;
+                continue;
             }
-            "int" => {
-                let unwrapped_arg = arg.unwrap();
-                if unwrapped_arg.starts_with("0x") {
-                    let arg =
-                        u8::from_str_radix(unwrapped_arg.trim_start_matches("0x"), 16).unwrap();
-                    out.push(Instruction::Interrupt as u8);
-                    out.push(arg);
-                } else {
-                    let arg = u8::from_str_radix(unwrapped_arg, 10).unwrap();
-                    out.push(Instruction::Interrupt as u8);
-                    out.push(arg);
+            Item::Space(size) if bss => *size,
+            Item::Space(_) => panic!("line {}: .space outside of .bss", n),
+            _ if bss => panic!("line {}: only .align and .space can follow .bss", n),
+            Item::Instr(instr, _) => 1 + instr.operand_size(),
+            Item::Bytes(bytes) => bytes.len(),
+            Item::Quad(_) => 8,
+        };
+        address += size;
+    }
+    let value = |n: usize, operand: &Operand| match operand {
+        Operand::Value(value) => *value,
+        Operand::Label(label) => match labels.get(label) {
+            Some(address) => *address as i64,
+            None => panic!("line {}: undefined label {}", n, label),
+        },
+        Operand::Variable(name) => {
+            panic!("line {}: %{} can only be used in inline assembly", n, name)
+        }
+    };
+    let mut out: Vec<u8> = Vec::new();
+    for (n, item) in &items {
+        match item {
+            Item::Instr(instr, operand) => {
+                out.push(*instr as u8);
+                if let Some(operand) = operand {
+                    let value = value(*n, operand);
+                    if instr.operand_size() == 1 && !(0..=255).contains(&value) {
+                        panic!("line {}: {} does not fit in a byte", n, value);
+                    }
+                    let bytes = value.to_le_bytes();
+                    out.extend_from_slice(&bytes[..instr.operand_size()]);
                 }
             }
-            "call" => out.push(Instruction::Call as u8),
-            "ret" => out.push(Instruction::Ret as u8),
-            _ => panic!("Unknown instruction"),
+            Item::Bytes(bytes) => out.extend_from_slice(bytes),
+            Item::Quad(operand) => out.extend_from_slice(&value(*n, operand).to_le_bytes()),
+            Item::Align(align) => out.resize(out.len().div_ceil(*align) * align, 0),
+            Item::Bss => break,
+            Item::Label(_) | Item::Space(_) => {}
         }
     }
 
     out
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_and_data() {
+        let code = "start: push data ; the address of data\n\
+                    jmp\n\
+                    .align 4\n\
+                    data: .byte 1, 0xff, -1\n\
+                    .quad start\n\
+                    .bss\n\
+                    .space 16";
+        let mut expected = vec![Instruction::Push as u8, 12, 0, 0, 0, 0, 0, 0, 0];
+        expected.extend([Instruction::Jump as u8, 0, 0, 1, 0xff, 0xff]);
+        expected.extend([0; 8]);
+        assert_eq!(assemble(code.to_string()), expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "line 2: undefined label nowhere")]
+    fn labels_must_be_defined() {
+        assemble("nop\npush nowhere".to_string());
+    }
+
+    #[test]
+    fn byte_operands_must_fit() {
+        assert!(parse_line("int 255").is_ok());
+        let message = "int needs an operand from 0 to 255";
+        assert_eq!(parse_line("int 300").err().as_deref(), Some(message));
+        assert_eq!(parse_line("int -1").err().as_deref(), Some(message));
+        assert_eq!(
+            assemble("sext 8\nint 0x0a".to_string()),
+            [
+                Instruction::SignExtend as u8,
+                8,
+                Instruction::Interrupt as u8,
+                10
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "line 1: 4096 does not fit in a byte")]
+    fn byte_labels_must_fit() {
+        assemble("int end\n.bss\n.space 4094\nend:".to_string());
+    }
+}
@@ -0,0 +1,94 @@
+use crate::display::DisplayInfo;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+// Writes the framebuffer as a binary PPM image. Pixels are 0xRRGGBB, as the window shows them.
+pub fn write_ppm(path: &Path, framebuffer: &[u32], display: &DisplayInfo) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    write!(out, "P6\n{} {}\n255\n", display.width, display.height)?;
+    for pixel in &framebuffer[..display.width * display.height] {
+        out.write_all(&[(pixel >> 16) as u8, (pixel >> 8) as u8, *pixel as u8])?;
+    }
+    out.flush()
+}
+
+// `path` with `tag` added to the file name, so `out.ppm` becomes `out-tag.ppm`.
+pub fn tagged(path: &Path, tag: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, tag, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, tag),
+    };
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::clock::Clock;
+    use crate::compiler::{compile, Options};
+    use crate::display::PixelFormat;
+    use crate::input::Script;
+    use crate::vm::VM;
+    use std::fs;
+
+    // Runs `tests/capture/NAME.c` with the clock fixed at 100 instructions to the
+    // millisecond and NAME.input, if there is one, as its input. What it leaves on
+    // the display must match NAME.ppm byte for byte. After a deliberate change,
+    // `vm vm --fixed-clock 100 --input NAME.input --capture NAME.ppm` remakes it.
+    pub fn check_capture(name: &str) {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/capture");
+        let path = dir.join(format!("{}.c", name));
+        let code = fs::read_to_string(&path).unwrap();
+        let compilation = compile(code, &path, &Options::default());
+        let mut vm = VM::new(compilation.output.expect("program compiles"));
+        vm.set_clock(Clock::Fixed(100));
+        let script = fs::read_to_string(dir.join(format!("{}.input", name))).unwrap_or_default();
+        let mut script = Script::parse(&script).unwrap();
+        while !vm.paused {
+            assert!(vm.steps < 10_000_000, "program does not halt");
+            script.feed(&mut vm);
+            vm.step().unwrap();
+        }
+        let captured = std::env::temp_dir().join(format!("capture-{}.ppm", name));
+        write_ppm(&captured, &vm.framebuffer(), &vm.display).unwrap();
+        let expected = dir.join(format!("{}.ppm", name));
+        assert!(
+            fs::read(&captured).unwrap() == fs::read(&expected).unwrap(),
+            "{} differs from {}",
+            captured.display(),
+            expected.display()
+        );
+        fs::remove_file(captured).unwrap();
+    }
+
+    #[test]
+    fn ppm_images() {
+        let display = DisplayInfo {
+            width: 2,
+            height: 1,
+            format: PixelFormat::Argb32,
+        };
+        let path = std::env::temp_dir().join(format!("capture-{}.ppm", std::process::id()));
+        // Anything past the display is not part of the image.
+        write_ppm(&path, &[0x123456, 0xff00ff, 0xabcdef], &display).unwrap();
+        let image = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(image, b"P6\n2 1\n255\n\x12\x34\x56\xff\x00\xff");
+    }
+
+    #[test]
+    fn tagged_names() {
+        assert_eq!(
+            tagged(Path::new("out/a.ppm"), "7"),
+            Path::new("out/a-7.ppm")
+        );
+        assert_eq!(tagged(Path::new("a"), "frame2"), Path::new("a-frame2"));
+    }
+
+    #[test]
+    fn shapes_and_clicks() {
+        check_capture("shapes");
+    }
+}
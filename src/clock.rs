@@ -0,0 +1,119 @@
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// Where the VM's time comes from.
+#[derive(Debug, Clone, Copy)]
+pub enum Clock {
+    // Real time. `epoch` is the time the VM started, in milliseconds since 1970.
+    Real { start: Instant, epoch: u64 },
+    // Time passes at this many instructions to the millisecond, starting at 1970,
+    // so a program sees the same times every run.
+    Fixed(u64),
+}
+impl Clock {
+    pub fn real() -> Clock {
+        let epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_millis() as u64);
+        Clock::Real {
+            start: Instant::now(),
+            epoch,
+        }
+    }
+    // Milliseconds since the VM started, once it has run `steps` instructions.
+    pub fn millis(&self, steps: u64) -> u64 {
+        match *self {
+            Clock::Real { start, .. } => start.elapsed().as_millis() as u64,
+            Clock::Fixed(rate) => steps / rate,
+        }
+    }
+    // Milliseconds since 1970.
+    pub fn time(&self, steps: u64) -> u64 {
+        match *self {
+            Clock::Real { epoch, .. } => epoch + self.millis(steps),
+            Clock::Fixed(_) => self.millis(steps),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerUnit {
+    Millis,
+    Instructions,
+}
+impl TimerUnit {
+    // The number programs use for the unit, as in badvm.h.
+    pub fn from_code(code: i64) -> Option<TimerUnit> {
+        match code {
+            0 => Some(TimerUnit::Millis),
+            1 => Some(TimerUnit::Instructions),
+            _ => None,
+        }
+    }
+}
+
+// Goes off every `period` milliseconds or instructions from `start`, which is in
+// the same unit.
+#[derive(Debug, Clone, Copy)]
+pub struct Timer {
+    pub period: u64,
+    pub unit: TimerUnit,
+    pub start: u64,
+}
+impl Timer {
+    // Times the timer has gone off by the time `now` in its unit.
+    pub fn count(&self, now: u64) -> u64 {
+        now.saturating_sub(self.start) / self.period
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::tests::check_capture;
+    use crate::compiler::{compile, Options};
+    use crate::vm::VM;
+    use std::path::Path;
+
+    #[test]
+    fn fixed_clocks() {
+        let clock = Clock::Fixed(100);
+        assert_eq!((clock.millis(250), clock.time(250)), (2, 2));
+        let timer = Timer {
+            period: 3,
+            unit: TimerUnit::Millis,
+            start: 5,
+        };
+        assert_eq!([4, 7, 8, 11].map(|now| timer.count(now)), [0, 0, 1, 2]);
+        assert_eq!(TimerUnit::from_code(2), None);
+    }
+
+    #[test]
+    fn programs_read_the_clock() {
+        let code = "#include \"badvm.h\"
+            int main(void) {
+                long before = TICKS;
+                int timer;
+                set_timer(2, TIMER_MILLIS);
+                while (TIMER < 3) {}
+                timer = TIMER;
+                set_timer(0, TIMER_MILLIS);
+                if (TIMER != 0) return -1;
+                // The registers cannot be written.
+                *(long *)CLOCK = 0;
+                return timer * 10 + (TICKS > before);
+            }";
+        let compilation = compile(code.to_string(), Path::new("test.c"), &Options::default());
+        let mut vm = VM::new(compilation.output.expect("program compiles"));
+        vm.set_clock(Clock::Fixed(10));
+        while !vm.paused {
+            assert!(vm.steps < 10_000_000, "program does not halt");
+            vm.step().unwrap();
+        }
+        assert_eq!(vm.exit_code, 31);
+    }
+
+    #[test]
+    fn fixed_clock_timer() {
+        check_capture("timer");
+    }
+}
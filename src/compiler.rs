@@ -1,12 +1,893 @@
-use self::{emitter::emit, lexer::lex, parser::parse};
+use self::{
+    emitter::assembly, emitter::emit, ir::Program, lexer::lex, linker::exports, linker::link,
+    optimizer::optimize, parser::parse, preprocessor::preprocess,
+};
+use std::path::Path;
 
+use self::diagnostic::has_errors;
+pub use self::diagnostic::{Diagnostic, Source};
+
+#[path = "diagnostic.rs"]
+mod diagnostic;
 #[path = "emitter.rs"]
 mod emitter;
+#[path = "ir.rs"]
+mod ir;
 #[path = "lexer.rs"]
 mod lexer;
+#[path = "linker.rs"]
+mod linker;
+#[path = "optimizer.rs"]
+mod optimizer;
 #[path = "parser.rs"]
 mod parser;
+#[path = "preprocessor.rs"]
+mod preprocessor;
+#[path = "types.rs"]
+mod types;
+
+// What `compile` produces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Emit {
+    #[default]
+    Binary,
+    // The IR as text, for debugging.
+    Ir,
+    // Assembly for `asm`, which gives the same binary.
+    Assembly,
+}
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub emit: Emit,
+    pub optimize: bool,
+}
+
+pub struct Compilation {
+    // None if any errors were found.
+    pub output: Option<Vec<u8>>,
+    pub diagnostics: Vec<Diagnostic>,
+    // Needed to render the diagnostics.
+    pub source: Source,
+}
+
+const RUNTIME: &str = include_str!("../include/runtime.c");
+
+// The runtime library, compiled like any other program. Its headers are builtin,
+// so it is given a path where nothing else can be found.
+fn runtime() -> Program {
+    let mut diagnostics = Vec::new();
+    let source = preprocess(RUNTIME, Path::new("<runtime>/runtime.c"), &mut diagnostics);
+    let tokens = lex(&source.text, &mut diagnostics);
+    let ast = parse(tokens, &mut diagnostics);
+    let library = ast.lower_library(&mut diagnostics);
+    if has_errors(&diagnostics) {
+        let errors: Vec<String> = diagnostics
+            .iter()
+            .map(|diagnostic| source.render(diagnostic))
+            .collect();
+        panic!(
+            "the runtime library does not compile:\n{}",
+            errors.join("\n")
+        );
+    }
+    library
+}
+
+// `path` is where the source came from, used to find included files.
+pub fn compile(code: String, path: &Path, options: &Options) -> Compilation {
+    let mut diagnostics = Vec::new();
+    let source = preprocess(&code, path, &mut diagnostics);
+    let mut output = None;
+    // Later stages would mostly report follow-on errors, so stop at the first one that fails.
+    if !has_errors(&diagnostics) {
+        let tokens = lex(&source.text, &mut diagnostics);
+        let ast = parse(tokens, &mut diagnostics);
+        if !has_errors(&diagnostics) {
+            let runtime = runtime();
+            let mut program = ast.lower(&exports(&runtime), &mut diagnostics);
+            if !has_errors(&diagnostics) {
+                link(&mut program, &runtime);
+                if options.optimize {
+                    optimize(&mut program);
+                }
+                output = match options.emit {
+                    Emit::Binary => emit(&program, options.optimize, &mut diagnostics),
+                    Emit::Ir => Some(program.to_string().into_bytes()),
+                    Emit::Assembly => {
+                        Some(assembly(&program, options.optimize, &source).into_bytes())
+                    }
+                };
+            }
+        }
+    }
+    // Report in source order; problems without a location come last.
+    diagnostics.sort_by_key(|diagnostic| diagnostic.span.map_or(usize::MAX, |span| span.start));
+    Compilation {
+        output,
+        diagnostics,
+        source,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::vm::{VMError, VM};
+
+    // Compiles `code` and runs it until it halts or faults, failing if it does not
+    // compile or runs for too long.
+    pub fn run(code: &str, options: &Options) -> Result<VM, VMError> {
+        let compilation = compile(code.to_string(), Path::new("test.c"), options);
+        for diagnostic in &compilation.diagnostics {
+            eprintln!("{}", compilation.source.render(diagnostic));
+        }
+        execute(compilation.output.expect("program compiles"))
+    }
+
+    fn execute(program: Vec<u8>) -> Result<VM, VMError> {
+        let mut vm = VM::new(program);
+        while !vm.paused {
+            assert!(vm.steps < 10_000_000, "program does not halt");
+            vm.step()?;
+        }
+        Ok(vm)
+    }
+
+    // The messages of the diagnostics for `code`.
+    fn errors(code: &str) -> Vec<String> {
+        compile(code.to_string(), Path::new("test.c"), &Options::default())
+            .diagnostics
+            .into_iter()
+            .map(|diagnostic| diagnostic.message)
+            .collect()
+    }
+
+    #[test]
+    fn calls_and_recursion() {
+        // Execution starts at main although fact comes first, and sub is defined after use.
+        let code = "int sub(int a, int b);
+            int fact(int n) {
+                if (n) return n * fact(n - 1);
+                return 1;
+            }
+            int main() { return sub(fact(5), sub(3, 1)); }
+            int sub(int a, int b) { return a - b; }";
+        assert_eq!(run(code, &Options::default()).unwrap().exit_code, 118);
+    }
+
+    #[test]
+    fn deep_recursion_overflows_the_stack() {
+        for code in [
+            "int f(void) { return f(); } int main(void) { return f(); }",
+            "int f(int x) { return f(x); } int main(void) { return f(1); }",
+        ] {
+            let Err(err) = run(code, &Options::default()) else {
+                panic!("endless recursion does not fault");
+            };
+            assert_eq!(err.message, "Stack overflow");
+        }
+    }
+
+    #[test]
+    fn globals_and_static_locals() {
+        let code = "int total = 5;
+            int counter() { static int n; n = n + 1; return n; }
+            int main() {
+                counter();
+                total = total + counter();
+                return total;
+            }";
+        assert_eq!(run(code, &Options::default()).unwrap().exit_code, 7);
+        // Initialized data is part of the image, zeroed data only takes up memory.
+        let image = |code: &str| {
+            compile(code.to_string(), Path::new("test.c"), &Options::default())
+                .output
+                .unwrap()
+        };
+        assert!(
+            image("char table[4] = {1, 2, 3}; int main() { return 0; }").ends_with(&[1, 2, 3, 0])
+        );
+        assert!(image("char big[1000]; int main() { return 0; }").len() < 1000);
+    }
+
+    #[test]
+    fn addresses_are_constants() {
+        let code = "int g = 5;
+            int table[3] = {1, 2, 3};
+            int *p = &g;
+            int *first = table;
+            void *any = &g;
+            int main(void) {
+                static int *q = &g;
+                static int local = 7;
+                static int *r = &local;
+                if (p != &g) return 1;
+                if (first[2] != 3) return 2;
+                if (q != &g) return 3;
+                if (*r != 7) return 4;
+                if (any != p) return 5;
+                return *p + table[1];
+            }";
+        let vm = run(code, &Options::default()).unwrap();
+        assert_eq!(vm.exit_code, 7);
+        assert_eq!(
+            errors("int g; int x = &g; char *c = &g; int main(void) { int l; static int *p = &l; return 0; }"),
+            [
+                "initializing int from int *",
+                "initializing char * from int *",
+                "initializer is not a constant"
+            ]
+        );
+    }
+
+    #[test]
+    fn statics_in_different_blocks_are_different() {
+        let code = "int count(void) { static int n; n = n + 1; return n; }
+            int main(void) {
+                int a;
+                int b;
+                { static int n = 1; a = n; }
+                { static int n = 2; b = n; }
+                count();
+                return a * 100 + b * 10 + count();
+            }";
+        assert_eq!(run(code, &Options::default()).unwrap().exit_code, 122);
+        assert_eq!(
+            errors("int main(void) { static int n; static int n; return 0; }"),
+            ["redeclaration of n"]
+        );
+    }
+
+    #[test]
+    fn pointers() {
+        let code = "int set(int *p, int value) { *p = value; return 0; }
+            int main() {
+                int x;
+                int *p;
+                int **pp;
+                p = &x;
+                pp = &p;
+                set(p, 5);
+                **pp = **pp + 1;
+                return x;
+            }";
+        assert_eq!(run(code, &Options::default()).unwrap().exit_code, 6);
+    }
+
+    #[test]
+    fn arrays_and_indexing() {
+        let code = "int g[4];
+            int sum(int a[], int n) {
+                int s;
+                s = 0;
+                while (n) { n = n - 1; s = s + a[n]; }
+                return s;
+            }
+            int main() {
+                int local[3];
+                int *p;
+                local[0] = 1;
+                local[1] = 2;
+                local[2] = 4;
+                p = local;
+                *(p + 1) = 10;
+                g[3] = 100;
+                return sum(local, 3) + *(g + 3) + (&local[2] - p);
+            }";
+        assert_eq!(run(code, &Options::default()).unwrap().exit_code, 117);
+    }
+
+    #[test]
+    fn integer_types_and_casts() {
+        let code = "unsigned char ub = 250;
+            short sh = -2;
+            unsigned int big = 4000000000;
+            long lg;
+            void set(long *p, long v) { *p = v; }
+            int check() {
+                char c = 200;
+                unsigned char u = c;
+                unsigned short us = -1;
+                unsigned x = big + big;
+                if (c != -56) return 1;
+                if (u != 200) return 2;
+                if (us != 65535) return 3;
+                if (x != 3705032704) return 4;
+                if (-1 < (unsigned)1) return 5;
+                if ((unsigned)-2 / 2 != 2147483647) return 6;
+                if (ub + 10 != 260) return 7;
+                if (sh * 3 != -6) return 8;
+                set(&lg, 5000000000);
+                if (lg != 5000000000) return 9;
+                if ((char)300 != 44) return 10;
+                return 0;
+            }
+            int main(void) { return check(); }";
+        assert_eq!(run(code, &Options::default()).unwrap().exit_code, 0);
+    }
+
+    #[test]
+    fn type_errors_are_reported() {
+        let code = "int main(void) {
+                int x;
+                int *p;
+                char *s;
+                void *v;
+                p = 5;
+                s = p;
+                v = p;
+                x = *v;
+                x = p < 1;
+                return p * 2;
+            }";
+        assert_eq!(
+            errors(code),
+            [
+                "incompatible types in assignment: int to int *",
+                "incompatible types in assignment: int * to char *",
+                "dereferencing a void pointer",
+                "cannot compare int * and int",
+                "invalid operands to *: int * and int",
+            ]
+        );
+    }
+
+    #[test]
+    fn arrays_must_fit_in_memory() {
+        assert_eq!(
+            errors("int a[9223372036854775807];"),
+            ["array is too large"]
+        );
+        assert_eq!(
+            errors("struct S { long x[100000]; };"),
+            ["array is too large"]
+        );
+        assert!(errors("char a[1000]; int main(void) { return 0; }").is_empty());
+    }
+
+    #[test]
+    fn structs_and_unions() {
+        let code = "struct point { char tag; int x; long y; };
+            union word { long l; unsigned char b[8]; };
+            struct node { int value; struct node *next; };
+            struct point origin = { 1, 2, 3 };
+            struct sprite { struct point pos; int frames[3]; };
+
+            void move(struct point *p, int dx) { p->x = p->x + dx; p->y = p->y * 2; }
+            int sum(struct node *n) {
+                int total = 0;
+                while (n) { total = total + n->value; n = n->next; }
+                return total;
+            }
+            int check() {
+                struct point p = { 5, 10 };
+                union word w;
+                struct node a; struct node b; struct node c;
+                struct sprite s;
+                if (sizeof(struct point) != 16) return 1;
+                if (sizeof p != 16) return 2;
+                if (sizeof(union word) != 8) return 3;
+                if (sizeof(struct sprite) != 32) return 4;
+                if (p.tag != 5) return 5;
+                if (p.x != 10) return 5;
+                if (p.y != 0) return 5;
+                move(&p, 7);
+                if (p.x != 17) return 6;
+                w.l = 258;
+                if (w.b[0] != 2) return 7;
+                if (w.b[1] != 1) return 7;
+                a.value = 1; a.next = &b; b.value = 2; b.next = &c; c.value = 3; c.next = 0;
+                if (sum(&a) != 6) return 8;
+                if (origin.y != 3) return 9;
+                move(&origin, 1);
+                if (origin.x != 3) return 10;
+                if (origin.y != 6) return 10;
+                s.pos.x = 4; s.frames[2] = 9;
+                if (s.pos.x + s.frames[2] != 13) return 11;
+                if (&s.frames[0] - (int *)&s != 4) return 12;
+                return 0;
+            }
+            int main(void) { return check(); }";
+        assert_eq!(run(code, &Options::default()).unwrap().exit_code, 0);
+    }
+
+    #[test]
+    fn struct_errors_are_reported() {
+        let code = "struct point { int x; };
+            int main(void) {
+                struct point p;
+                struct shape *s;
+                int n;
+                n = p.y;
+                n = s->x;
+                return p;
+            }";
+        assert_eq!(
+            errors(code),
+            [
+                "struct point has no member named y",
+                "struct shape has no member named x",
+                "incompatible types in return: struct point to int",
+            ]
+        );
+    }
+
+    #[test]
+    fn members_need_complete_types() {
+        assert_eq!(
+            errors("struct S { struct S s; };"),
+            ["member s has incomplete type struct S"]
+        );
+        assert_eq!(
+            errors("struct T; struct S { int x; struct T t[2]; };"),
+            ["member t has incomplete type struct T [2]"]
+        );
+        let list = "struct S { struct S *next; int x; }; int main(void) { return 0; }";
+        assert!(errors(list).is_empty());
+    }
+
+    #[test]
+    fn string_and_char_literals() {
+        let code = "char *greeting = \"hello\";
+            char *table[3] = { \"ab\", 0, \"ab\" };
+            char name[] = \"vm\";
+            int strlen(char *s) { int n = 0; while (s[n]) n = n + 1; return n; }
+            int check() {
+                char buf[8] = \"xyz\";
+                char *p = \"tab\\there\\n\";
+                char exact[2] = \"hi\";
+                if (strlen(greeting) != 5) return 1;
+                if (greeting[1] != 'e') return 2;
+                if (table[0] != table[2]) return 3;
+                if (sizeof name != 3) return 4;
+                if (sizeof \"abc\" != 4) return 5;
+                if (buf[2] != 'z') return 6;
+                if (buf[3] != 0) return 6;
+                if (p[3] != 9) return 7;
+                if (strlen(p) != 9) return 8;
+                if ('\\x41' != 65) return 9;
+                if ('\\377' != -1) return 10;
+                if ('\\'' != 39) return 11;
+                if (strlen(\"con\" \"cat\") != 6) return 12;
+                if (exact[1] != 'i') return 13;
+                if (\"xyz\"[1] != 'y') return 14;
+                if (strlen(\"\\\"\\\\\") != 2) return 15;
+                return 0;
+            }
+            int main(void) { return check(); }";
+        assert_eq!(run(code, &Options::default()).unwrap().exit_code, 0);
+    }
+
+    #[test]
+    fn string_initializers_are_checked() {
+        let code = "int n = \"1\";
+            char *p = 'a';
+            int main(void) { return 0; }";
+        assert_eq!(
+            errors(code),
+            [
+                "initializing int from a string literal",
+                "initializing char * from an integer needs a cast",
+            ]
+        );
+    }
+
+    #[test]
+    fn string_literals_are_read_only() {
+        let code = "char buf[4] = \"abc\";
+            int main(void) {
+                char *s = \"abc\";
+                buf[0] = 'x';
+                if (buf[0] != 'x') return 1;
+                s[0] = 'x';
+                return 2;
+            }";
+        let Err(err) = run(code, &Options::default()) else {
+            panic!("writing to a string literal does not fault");
+        };
+        assert_eq!(err.message, "Write to read-only memory");
+    }
+
+    #[test]
+    fn comments_and_macros() {
+        let code = "/* header comment
+               spanning lines */
+            #include \"badvm.h\"
+            #define TWICE(x) ((x) + (x))
+            #define SQUARE(x) ((x) * (x))
+            #define SUM3(a, b, \\
+                         c) ((a) + (b) + (c))
+            #define ANSWER 42
+            #define STR \"ANSWER // not a comment\"
+            #define EMPTY()
+
+            #ifdef ANSWER
+            int answer = ANSWER; // trailing comment
+            #else
+            int answer = 0;
+            #endif
+            #ifndef BADVM_H
+            int broken;
+            #endif
+
+            int check() {
+                int self = 1;
+            #define self self + 1
+                char *s = STR;
+                put_pixel(1, 2, WHITE);
+                if (SQUARE(3 + 1) != 16) return 2;
+                if (SUM3(1,
+                         SQUARE(2),
+                         TWICE(3)) != 11) return 3;
+                if (answer != 42) return 4;
+                if (s[0] != 'A') return 5;
+                if (s[7] != '/') return 6;
+                EMPTY()
+                if (self != 2) return 7;
+                if (WIDTH * HEIGHT != 4096) return 9;
+                return 0 /* inline */ ;
+            }
+            int main(void) { return check(); }";
+        assert_eq!(run(code, &Options::default()).unwrap().exit_code, 0);
+    }
+
+    #[test]
+    fn includes_are_found_next_to_the_source() {
+        let dir = std::env::temp_dir().join(format!("cc-include-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("helper.h"), "int helper(void) { return 3; }\n").unwrap();
+        let code = "#include \"helper.h\"
+            int main(void) { return helper(); }";
+        let program = compile(code.to_string(), &dir.join("test.c"), &Options::default());
+        std::fs::remove_dir_all(&dir).unwrap();
+        let vm = execute(program.output.expect("program compiles")).unwrap();
+        assert_eq!(vm.exit_code, 3);
+    }
+
+    #[test]
+    fn preprocessor_errors_are_reported() {
+        let code = "#define 1 2
+            #define F(a b) a
+            #define N 1
+            #define N 2
+            #else
+            #include <stdio.h>
+            #pragma once
+            #ifdef N
+            int main(void) { return 0; }";
+        assert_eq!(
+            errors(code),
+            [
+                "macro name must be an identifier",
+                "invalid parameter list for F",
+                "N redefined",
+                "#else without #ifdef",
+                "#include expects \"file\"",
+                "unknown directive #pragma",
+                "unterminated #ifdef in test.c",
+            ]
+        );
+    }
+
+    #[test]
+    fn undeclared_calls_are_one_error() {
+        assert_eq!(
+            errors("int main(void) { return foo(1, 2); }"),
+            ["call to undeclared function foo"]
+        );
+        assert_eq!(
+            errors("int foo(int x); int main(void) { return foo(); }"),
+            ["foo expects 1 arguments but got 0"]
+        );
+    }
+
+    #[test]
+    fn ir_is_printed_as_text() {
+        let code = "int g;
+            int main(void) {
+                if (g) g = 2;
+                return g;
+            }";
+        let options = Options {
+            emit: Emit::Ir,
+            ..Default::default()
+        };
+        let compilation = compile(code.to_string(), Path::new("test.c"), &options);
+        let ir = String::from_utf8(compilation.output.unwrap()).unwrap();
+        let expected = concat!(
+            "fn <entry> (frame 0):\n",
+            "b0:\n",
+            "    addr .rodata_end\n",
+            "    int 13\n",
+            "    addr main\n",
+            "    call\n",
+            "    exit\n",
+            "\n",
+            "fn main (frame 0):\n",
+            "b0:\n",
+            "    ; at 23\n",
+            "    ; at 56\n",
+            "    addr g\n",
+            "    load i32\n",
+            "    br b1, b2\n",
+            "b1:\n",
+            "    ; at 59\n",
+            "    push 2\n",
+            "    dup\n",
+            "    addr g\n",
+            "    store 32\n",
+            "    pop\n",
+            "    jmp b2\n",
+            "b2:\n",
+            "    ; at 82\n",
+            "    addr g\n",
+            "    load i32\n",
+            "    ret\n",
+            "b3:\n",
+            "    push 0\n",
+            "    ret\n",
+            "\n",
+            "g: 4 bytes, align 4\n",
+        );
+        assert_eq!(ir, expected);
+    }
+
+    #[test]
+    fn main_returns_the_exit_code() {
+        let code = "int main(void) { return -3; }";
+        assert_eq!(run(code, &Options::default()).unwrap().exit_code, -3);
+        // Without a return value main exits with 0, whatever is on the stack.
+        let code = "void main(void) { 5; }";
+        assert_eq!(run(code, &Options::default()).unwrap().exit_code, 0);
+    }
+
+    #[test]
+    fn interrupts() {
+        let code = "#include \"badvm.h\"
+            int main(void) {
+                set_display(64, 64, FORMAT_ARGB32);
+                put_pixel(3, 2, RED);
+                put_pixel_index(WIDTH - 1, rgb(1, 2, 3));
+                return 0;
+            }";
+        let vm = run(code, &Options::default()).unwrap();
+        assert_eq!(vm.framebuffer()[2 * 64 + 3], 0xff0000);
+        assert_eq!(vm.framebuffer()[63], 0x010203);
+        let code = "int main(void) { __interrupt(0x01, 64, 0, 0); return 0; }";
+        let Err(err) = run(code, &Options::default()) else {
+            panic!("the pixel was drawn");
+        };
+        assert_eq!(err.message, "Pixel out of bounds");
+    }
+
+    #[test]
+    fn interrupts_are_checked() {
+        let code = "struct point { int x; int y; };
+            int main(void) {
+                int n = 1;
+                struct point p;
+                __interrupt();
+                __interrupt(n, 1);
+                __interrupt(0x7f);
+                __interrupt(0x00, 1);
+                __interrupt(0x00, 1, p);
+                return 0;
+            }";
+        assert_eq!(
+            errors(code),
+            [
+                "__interrupt needs an interrupt number",
+                "interrupt number must be a constant",
+                "unknown interrupt 127",
+                "interrupt 0 (write pixel by index) expects 2 arguments but got 1",
+                "cannot pass struct point to an interrupt",
+            ]
+        );
+    }
+
+    #[test]
+    fn inline_asm_errors() {
+        let code = "int main(void) {
+                int x;
+                asm(\"push %y\\n push .a\\n .a:\\n .a:\\n dup 1\\n .quad 0\\n bogus\");
+                asm(\"push .nowhere\\n jmp\");
+                return x;
+            }";
+        assert_eq!(
+            errors(code),
+            [
+                "undeclared variable y",
+                "label .a defined twice",
+                "inline assembly line 5: dup takes 0 operands",
+                "directives cannot be used in inline assembly",
+                "inline assembly line 7: unknown instruction bogus",
+                "undefined label .nowhere",
+            ]
+        );
+    }
+
+    #[test]
+    fn inline_asm_is_checked() {
+        assert_eq!(
+            errors("int main(void) { asm(\"push 1; add\"); return 0; }"),
+            ["inline assembly line 1: text after ; would be ignored"]
+        );
+        assert_eq!(
+            errors("int main(void) { asm(\"push 0\\nint 300\"); return 0; }"),
+            ["inline assembly line 2: int needs an operand from 0 to 255"]
+        );
+        let code = "int main(void) { int r; asm(\"push 7;\\n push %r\\n storei64\"); return r; }";
+        assert_eq!(run(code, &Options::default()).unwrap().exit_code, 7);
+    }
+
+    #[test]
+    fn steps_and_frames_are_counted() {
+        let code = "#include \"badvm.h\"
+            int main(void) {
+                end_frame();
+                end_frame();
+                return 0;
+            }";
+        let vm = run(code, &Options::default()).unwrap();
+        assert_eq!(vm.frames, 2);
+        let code = "int main(void) { return 0; }";
+        // push .rodata_end, int 13, push main, call, enter, push 0, ret and exit.
+        assert_eq!(run(code, &Options::default()).unwrap().steps, 8);
+    }
+
+    #[test]
+    fn display_modes() {
+        let code = "#include \"badvm.h\"
+            int main(void) {
+                set_display(100, 20, FORMAT_MONO1);
+                put_pixel(99, 19, WHITE);
+                return WIDTH + HEIGHT + FORMAT;
+            }";
+        let vm = run(code, &Options::default()).unwrap();
+        assert_eq!(vm.exit_code, 122);
+        let pixels = vm.framebuffer();
+        assert_eq!(pixels.len(), 2000);
+        assert_eq!(pixels.iter().filter(|&&pixel| pixel == 0xffffff).count(), 1);
+        assert_eq!(pixels[1999], 0xffffff);
+        let code = "#include \"badvm.h\"
+            int main(void) { set_display(512, 512, FORMAT_ARGB32); return 0; }";
+        let Err(err) = run(code, &Options::default()) else {
+            panic!("the mode was set");
+        };
+        assert_eq!(err.message, "Unsupported display mode 512x512 Argb32");
+    }
+
+    #[test]
+    fn palette() {
+        let code = "#include \"badvm.h\"
+            int main(void) {
+                unsigned char *fb = (unsigned char *)FRAMEBUFFER;
+                fb[0] = 1;
+                fb[1] = 2;
+                fb[2] = 3;
+                set_palette(1, RED);
+                ((int *)PALETTE)[2] = GREEN;
+                return ((int *)PALETTE)[1] == RED;
+            }";
+        let vm = run(code, &Options::default()).unwrap();
+        assert_eq!(vm.exit_code, 1);
+        // Entries start out grey.
+        assert_eq!(vm.framebuffer()[..4], [0xff0000, 0x00ff00, 0x030303, 0]);
+        let code = "#include \"badvm.h\"
+            int main(void) { set_palette(256, 0); return 0; }";
+        let Err(err) = run(code, &Options::default()) else {
+            panic!("the palette was set");
+        };
+        assert_eq!(err.message, "Palette index out of bounds");
+    }
+
+    #[test]
+    fn graphics_interrupts() {
+        let code = "#include \"badvm.h\"
+            unsigned char sprite[4] = { 7, 0, 0, 7 };
+            int main(void) {
+                set_display(4, 4, FORMAT_INDEXED8);
+                clear_screen(1);
+                fill_rect(-1, 2, 3, 9, 2);
+                draw_line(3, 0, 3, 3, 3);
+                blit(sprite, 1, 0, 2, 2, 0);
+                return 0;
+            }";
+        let vm = run(code, &Options::default()).unwrap();
+        let pixels: Vec<u32> = vm.framebuffer().iter().map(|pixel| pixel & 0xff).collect();
+        assert_eq!(pixels, [1, 7, 1, 3, 1, 1, 7, 3, 2, 2, 1, 3, 2, 2, 1, 3]);
+    }
+
+    #[test]
+    fn draw_text() {
+        let code = "#include \"badvm.h\"
+            int main(void) {
+                set_display(4, 5, FORMAT_INDEXED8);
+                draw_text(\"-\", 0, 0, 9);
+                return 0;
+            }";
+        let vm = run(code, &Options::default()).unwrap();
+        let lit: Vec<usize> = (0..20).filter(|&i| vm.framebuffer()[i] != 0).collect();
+        assert_eq!(lit, [8, 9, 10]);
+        // The string has to end before memory does.
+        let code = "#include \"badvm.h\"
+            int main(void) {
+                char *end = (char *)0x4ffff;
+                *end = 'x';
+                draw_text(end, 0, 0, 9);
+                return 0;
+            }";
+        let Err(err) = run(code, &Options::default()) else {
+            panic!("the text was drawn");
+        };
+        assert_eq!(err.message, "String out of bounds");
+    }
+
+    #[test]
+    fn present_finishes_a_frame_and_asks_to_wait() {
+        let code = "#include \"badvm.h\"
+            int main(void) { end_frame(); return 0; }";
+        let vm = run(code, &Options::default()).unwrap();
+        assert_eq!((vm.frames, vm.vsync), (1, false));
+        let code = "#include \"badvm.h\"
+            int main(void) { present(); return 0; }";
+        let vm = run(code, &Options::default()).unwrap();
+        assert_eq!((vm.frames, vm.vsync), (1, true));
+    }
+
+    #[test]
+    fn sizeof_does_not_evaluate() {
+        let code = "long f(void);
+            int main(void) {
+                char *s = \"ab\";
+                return sizeof(f()) + sizeof(\"xyz\"[0]) + sizeof(s);
+            }";
+        let compilation = compile(
+            code.to_string(),
+            Path::new("test.c"),
+            &Options {
+                emit: Emit::Ir,
+                ..Options::default()
+            },
+        );
+        assert!(compilation.diagnostics.is_empty());
+        let ir = String::from_utf8(compilation.output.unwrap()).unwrap();
+        assert!(ir.contains(".str0") && !ir.contains(".str1"));
+        assert_eq!(run(code, &Options::default()).unwrap().exit_code, 17);
+    }
 
-pub fn compile(code: String) -> Vec<u8> {
-    emit(parse(lex(code)))
+    #[test]
+    fn assembly_gives_the_same_binary() {
+        let code = "#include \"stdlib.h\"
+            struct point { char tag; long x; short y; };
+            struct point origin = {'o', 0, 0};
+            char *names[2] = {\"first\", \"second\"};
+            int table[4] = {1, -2, 3};
+            long zeroed[3];
+            int count(void) { static int n; n = n + 1; return n; }
+            int main(void) {
+                char buf[16];
+                struct point p;
+                int i = 0;
+                p.x = 3;
+                while (i < 4) {
+                    if (table[i] < 0) p.x = p.x + count();
+                    i = i + 1;
+                }
+                asm(\".again:\\n push 0\\n push .done\\n jz\\n push .again\\n jmp\\n .done:\");
+                itoa(p.x, buf, 16);
+                zeroed[1] = names[1][0];
+                return buf[0] + zeroed[1] + origin.tag;
+            }";
+        for optimize in [false, true] {
+            let output = |emit| {
+                let options = Options { emit, optimize };
+                let compilation = compile(code.to_string(), Path::new("test.c"), &options);
+                assert!(compilation.diagnostics.is_empty());
+                compilation.output.unwrap()
+            };
+            let assembly = String::from_utf8(output(Emit::Assembly)).unwrap();
+            assert_eq!(crate::asm::assemble(assembly), output(Emit::Binary));
+        }
+    }
 }
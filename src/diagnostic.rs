@@ -0,0 +1,222 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+// A range of bytes in the preprocessed source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.start, other.end.max(self.start))
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    // None for problems that belong to the program as a whole.
+    pub span: Option<Span>,
+    pub message: String,
+    pub notes: Vec<String>,
+}
+impl Diagnostic {
+    pub fn error(span: Option<Span>, message: String) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            span,
+            message,
+            notes: Vec::new(),
+        }
+    }
+    pub fn warning(span: Option<Span>, message: String) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..Diagnostic::error(span, message)
+        }
+    }
+    pub fn with_note(mut self, note: String) -> Diagnostic {
+        self.notes.push(note);
+        self
+    }
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+pub fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics.iter().any(Diagnostic::is_error)
+}
+// The preprocessed text together with where each of its lines came from.
+#[derive(Debug, Default)]
+pub struct Source {
+    pub text: String,
+    // Every file that was read, with its original contents.
+    files: Vec<(PathBuf, String)>,
+    // For each line of `text`, the index into `files` and the line number there.
+    lines: Vec<(usize, usize)>,
+    // The offset just past the end of each line of `text`.
+    ends: Vec<usize>,
+}
+impl Source {
+    pub fn add_file(&mut self, path: &Path, code: &str) -> usize {
+        self.files.push((path.to_path_buf(), code.to_string()));
+        self.files.len() - 1
+    }
+    // Records that the line of `text` just ended came from `line` of `file`.
+    pub fn add_line(&mut self, file: usize, line: usize) {
+        self.lines.push((file, line));
+        self.ends.push(self.text.len());
+    }
+    // The index into `lines` of the line containing `offset`.
+    fn line_index(&self, offset: usize) -> usize {
+        let index = self.ends.partition_point(|end| *end <= offset);
+        index.min(self.lines.len() - 1)
+    }
+    // The file and line number `offset` came from, with that line as written.
+    pub fn location(&self, offset: usize) -> Option<(&Path, usize, &str)> {
+        if self.lines.is_empty() {
+            return None;
+        }
+        let (file, number) = self.lines[self.line_index(offset)];
+        let (path, code) = &self.files[file];
+        Some((path, number, code.lines().nth(number - 1).unwrap_or("")))
+    }
+    // Renders a diagnostic with the offending line and a caret under the span.
+    pub fn render(&self, diagnostic: &Diagnostic) -> String {
+        let mut out = String::new();
+        let span = match diagnostic.span {
+            Some(span) if !self.lines.is_empty() => span,
+            _ => {
+                out.push_str(&format!("{}: {}", diagnostic.severity, diagnostic.message));
+                return self.render_notes(diagnostic, out);
+            }
+        };
+        let start = span.start.min(self.text.len());
+        let line_start = self.text[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = self.text[start..]
+            .find('\n')
+            .map_or(self.text.len(), |i| start + i);
+        let (path, number, original) = self.location(start).unwrap();
+        let column = self.text[line_start..start].chars().count() + 1;
+        out.push_str(&format!(
+            "{}:{}:{}: {}: {}\n",
+            path.display(),
+            number,
+            column,
+            diagnostic.severity,
+            diagnostic.message
+        ));
+        // Show the preprocessed line, since that is what the caret lines up with.
+        // Only a line that preprocessed to nothing, such as a directive, is shown
+        // as written instead.
+        let expanded = &self.text[line_start..line_end];
+        let shown = if expanded.trim().is_empty() {
+            original
+        } else {
+            expanded
+        };
+        let width = self.text[start..span.end.clamp(start, line_end)]
+            .chars()
+            .count()
+            .max(1);
+        let gutter = " ".repeat(number.to_string().len());
+        out.push_str(&format!("{} | {}\n", number, shown.trim_end()));
+        // Tabs are kept so the caret stays under the right character.
+        let padding: String = shown
+            .chars()
+            .take(column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        out.push_str(&format!("{} | {}{}", gutter, padding, "^".repeat(width)));
+        self.render_notes(diagnostic, out)
+    }
+    fn render_notes(&self, diagnostic: &Diagnostic, mut out: String) -> String {
+        for note in &diagnostic.notes {
+            out.push_str(&format!("\nnote: {}", note));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::{compile, Options};
+    use std::path::Path;
+
+    // Every diagnostic for `code`, as `cc` prints it.
+    fn rendered(code: &str) -> Vec<String> {
+        let compilation = compile(code.to_string(), Path::new("test.c"), &Options::default());
+        let source = &compilation.source;
+        compilation
+            .diagnostics
+            .iter()
+            .map(|diagnostic| source.render(diagnostic))
+            .collect()
+    }
+
+    #[test]
+    fn errors_come_in_source_order() {
+        // The lexer finds the stray character before the parser sees line 2.
+        let code = "int main(void) {\n\tint x = ;\n\tint y = 1 @;\n}\n";
+        assert_eq!(
+            rendered(code),
+            [
+                "test.c:2:10: error: expected expression but got `;`\n\
+                 2 | \tint x = ;\n  \
+                   | \t        ^",
+                "test.c:3:12: error: unexpected character '@'\n\
+                 3 | \tint y = 1 @;\n  \
+                   | \t          ^",
+            ]
+        );
+    }
+
+    #[test]
+    fn lines_are_shown_as_preprocessed() {
+        assert_eq!(
+            rendered("#define N 1 +\nint a = N;\n"),
+            ["test.c:2:12: error: expected expression but got `;`\n\
+              2 | int a = 1 +;\n  \
+                |            ^"]
+        );
+        // A directive leaves its line empty, so the original is shown.
+        assert_eq!(
+            rendered("int a;\n#if\n"),
+            ["test.c:2:1: error: unknown directive #if\n\
+              2 | #if\n  \
+                | ^"]
+        );
+    }
+
+    #[test]
+    fn program_wide_errors_have_no_location() {
+        assert_eq!(rendered("int x;"), ["error: undefined reference to main"]);
+    }
+
+    #[test]
+    fn notes_follow_their_error() {
+        assert_eq!(
+            rendered("int f(int a);\nint f(void) { return 0; }\nint main(void) { return f(); }\n"),
+            ["test.c:2:5: error: conflicting declarations of f\n\
+              2 | int f(void) { return 0; }\n  \
+                |     ^\n\
+              note: previously declared as int f(int)"]
+        );
+    }
+}
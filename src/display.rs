@@ -0,0 +1,368 @@
+use super::font::{glyph, GLYPH_HEIGHT, GLYPH_WIDTH};
+use std::convert::TryInto;
+use std::ops::Range;
+
+// How the bytes of the framebuffer turn into pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    // Four bytes per pixel, 0xAARRGGBB in little endian. Alpha is ignored.
+    Argb32,
+    // One byte per pixel, an index into the palette.
+    Indexed8,
+    // One bit per pixel, eight to a byte from the top bit down, set for white.
+    // Every row starts on a new byte.
+    Mono1,
+}
+impl PixelFormat {
+    pub fn from_name(name: &str) -> Option<PixelFormat> {
+        match name {
+            "argb32" => Some(PixelFormat::Argb32),
+            "indexed8" => Some(PixelFormat::Indexed8),
+            "mono1" => Some(PixelFormat::Mono1),
+            _ => None,
+        }
+    }
+    // The number programs use for the format, as in badvm.h.
+    pub fn code(self) -> u32 {
+        match self {
+            PixelFormat::Argb32 => 0,
+            PixelFormat::Indexed8 => 1,
+            PixelFormat::Mono1 => 2,
+        }
+    }
+    pub fn from_code(code: i64) -> Option<PixelFormat> {
+        match code {
+            0 => Some(PixelFormat::Argb32),
+            1 => Some(PixelFormat::Indexed8),
+            2 => Some(PixelFormat::Mono1),
+            _ => None,
+        }
+    }
+}
+
+// A rectangle in display coordinates, which may be partly or entirely off the display.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: i64,
+    pub y: i64,
+    pub width: i64,
+    pub height: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisplayInfo {
+    pub width: usize,
+    pub height: usize,
+    pub format: PixelFormat,
+}
+impl Default for DisplayInfo {
+    // Byte writes to the framebuffer show up as grey, one byte per pixel.
+    fn default() -> DisplayInfo {
+        DisplayInfo {
+            width: 64,
+            height: 64,
+            format: PixelFormat::Indexed8,
+        }
+    }
+}
+impl DisplayInfo {
+    // Bytes from the start of one row to the next.
+    pub fn pitch(&self) -> usize {
+        match self.format {
+            PixelFormat::Argb32 => self.width * 4,
+            PixelFormat::Indexed8 => self.width,
+            PixelFormat::Mono1 => self.width.div_ceil(8),
+        }
+    }
+    // Bytes of the framebuffer that are on display.
+    pub fn size(&self) -> usize {
+        self.pitch() * self.height
+    }
+    // Bytes per pixel of a sprite, which is one byte per pixel unless colors need more.
+    pub fn sprite_pixel_size(&self) -> usize {
+        match self.format {
+            PixelFormat::Argb32 => 4,
+            PixelFormat::Indexed8 | PixelFormat::Mono1 => 1,
+        }
+    }
+    // The columns and rows of a rectangle that are on the display.
+    fn clip(&self, rect: Rect) -> (Range<usize>, Range<usize>) {
+        let span = |start: i64, length: i64, limit: usize| {
+            let end = start.saturating_add(length.max(0)).clamp(0, limit as i64);
+            start.clamp(0, end) as usize..end as usize
+        };
+        (
+            span(rect.x, rect.width, self.width),
+            span(rect.y, rect.height, self.height),
+        )
+    }
+    pub fn fill(&self, framebuffer: &mut [u8], rect: Rect, color: i64) {
+        let (columns, rows) = self.clip(rect);
+        for y in rows {
+            for x in columns.clone() {
+                self.put(framebuffer, x, y, color);
+            }
+        }
+    }
+    // Draws the pixels nearest to the line from (x0, y0) to (x1, y1), one per step
+    // along the longer axis. Only the steps that are on the display are taken.
+    pub fn line(&self, framebuffer: &mut [u8], x0: i64, y0: i64, x1: i64, y1: i64, color: i64) {
+        // Wide enough that nothing below overflows.
+        let (x0, y0, x1, y1) = (x0 as i128, y0 as i128, x1 as i128, y1 as i128);
+        let (dx, dy) = ((x1 - x0).abs(), (y1 - y0).abs());
+        let (sx, sy) = ((x1 - x0).signum(), (y1 - y0).signum());
+        let (width, height) = (self.width as i128, self.height as i128);
+        // a is the longer axis and b the other one.
+        let (a0, b0, da, db, sa, sb, a_limit, b_limit) = if dx >= dy {
+            (x0, y0, dx, dy, sx, sy, width, height)
+        } else {
+            (y0, x0, dy, dx, sy, sx, height, width)
+        };
+        // Step i is at a0 + sa * i, which has to be in 0..a_limit.
+        let (first, last) = if sa < 0 {
+            (a0 - a_limit + 1, a0)
+        } else {
+            (-a0, a_limit - 1 - a0)
+        };
+        for i in first.max(0)..=last.min(da) {
+            let a = a0 + sa * i;
+            // Rounds b0 + sb * i * db / da to the nearest pixel.
+            let b = match da {
+                0 => b0,
+                _ => b0 + sb * ((2 * i * db + da) / (2 * da)),
+            };
+            if (0..b_limit).contains(&b) {
+                let (x, y) = if dx >= dy { (a, b) } else { (b, a) };
+                self.put(framebuffer, x as usize, y as usize, color);
+            }
+        }
+    }
+    // Copies a sprite the size of `rect` to it, leaving out pixels equal to `key`.
+    pub fn blit(&self, framebuffer: &mut [u8], sprite: &[u8], rect: Rect, key: i64) {
+        let size = self.sprite_pixel_size();
+        let (columns, rows) = self.clip(rect);
+        for row in rows {
+            for column in columns.clone() {
+                let (x, y) = (column as i64 - rect.x, row as i64 - rect.y);
+                let at = (y * rect.width + x) as usize * size;
+                let pixel = &sprite[at..at + size];
+                let color = match size {
+                    4 => u32::from_le_bytes(pixel.try_into().unwrap()) as i64,
+                    _ => pixel[0] as i64,
+                };
+                if color != key & ((1 << (size * 8)) - 1) {
+                    self.put(framebuffer, column, row, color);
+                }
+            }
+        }
+    }
+    // Draws `text` with its top left at (x, y), one pixel between characters and
+    // lines. A newline starts the next line back at x.
+    pub fn text(&self, framebuffer: &mut [u8], text: &[u8], x: i64, y: i64, color: i64) {
+        let (mut left, mut top) = (x, y);
+        for &c in text {
+            if c == b'\n' {
+                left = x;
+                top = top.saturating_add(GLYPH_HEIGHT as i64 + 1);
+                continue;
+            }
+            let bits = glyph(c);
+            for row in 0..GLYPH_HEIGHT {
+                for column in 0..GLYPH_WIDTH {
+                    let shift = (GLYPH_HEIGHT - 1 - row) * GLYPH_WIDTH + GLYPH_WIDTH - 1 - column;
+                    let (px, py) = (
+                        left.saturating_add(column as i64),
+                        top.saturating_add(row as i64),
+                    );
+                    let on_display = (0..self.width as i64).contains(&px)
+                        && (0..self.height as i64).contains(&py);
+                    if bits >> shift & 1 != 0 && on_display {
+                        self.put(framebuffer, px as usize, py as usize, color);
+                    }
+                }
+            }
+            left = left.saturating_add(GLYPH_WIDTH as i64 + 1);
+        }
+    }
+    // Stores `color` as pixel (x, y), keeping as many low bits as the format has.
+    // The caller checks that the pixel is on the display.
+    pub fn put(&self, framebuffer: &mut [u8], x: usize, y: usize, color: i64) {
+        let row = y * self.pitch();
+        match self.format {
+            PixelFormat::Argb32 => {
+                let at = row + x * 4;
+                framebuffer[at..at + 4].copy_from_slice(&(color as u32).to_le_bytes());
+            }
+            PixelFormat::Indexed8 => framebuffer[row + x] = color as u8,
+            PixelFormat::Mono1 => {
+                let bit = 0x80 >> (x % 8);
+                if color & 1 != 0 {
+                    framebuffer[row + x / 8] |= bit;
+                } else {
+                    framebuffer[row + x / 8] &= !bit;
+                }
+            }
+        }
+    }
+    // The pixels on display as 0xRRGGBB, row by row.
+    pub fn render(&self, framebuffer: &[u8], palette: &[u32]) -> Vec<u32> {
+        let mut pixels = Vec::with_capacity(self.width * self.height);
+        for y in 0..self.height {
+            let row = &framebuffer[y * self.pitch()..(y + 1) * self.pitch()];
+            pixels.extend((0..self.width).map(|x| match self.format {
+                PixelFormat::Argb32 => {
+                    let bytes = row[x * 4..x * 4 + 4].try_into().unwrap();
+                    u32::from_le_bytes(bytes) & 0xffffff
+                }
+                PixelFormat::Indexed8 => palette[row[x] as usize],
+                PixelFormat::Mono1 if row[x / 8] & (0x80 >> (x % 8)) != 0 => 0xffffff,
+                PixelFormat::Mono1 => 0,
+            }));
+        }
+        pixels
+    }
+}
+
+// Palette entry N is grey with brightness N.
+pub fn grey_palette() -> Vec<u32> {
+    (0..256).map(|level| level * 0x010101).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Puts each of `colors` in a row of a one-pixel-high display, and renders it.
+    fn round_trip(format: PixelFormat, colors: &[i64]) -> (Vec<u8>, Vec<u32>) {
+        let display = DisplayInfo {
+            width: colors.len(),
+            height: 1,
+            format,
+        };
+        let mut framebuffer = vec![0; display.size()];
+        for (x, color) in colors.iter().enumerate() {
+            display.put(&mut framebuffer, x, 0, *color);
+        }
+        let pixels = display.render(&framebuffer, &grey_palette());
+        (framebuffer, pixels)
+    }
+
+    #[test]
+    fn argb32() {
+        let (bytes, pixels) = round_trip(PixelFormat::Argb32, &[0x7f123456, -1]);
+        assert_eq!(bytes, [0x56, 0x34, 0x12, 0x7f, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(pixels, [0x123456, 0xffffff]);
+    }
+
+    #[test]
+    fn indexed8() {
+        let (bytes, pixels) = round_trip(PixelFormat::Indexed8, &[0x80, 0x1ff]);
+        assert_eq!(bytes, [0x80, 0xff]);
+        assert_eq!(pixels, [0x808080, 0xffffff]);
+    }
+
+    #[test]
+    fn mono1() {
+        let colors = [1, 0, 1, 1, 0, 0, 0, 0, 3, 2];
+        let (bytes, pixels) = round_trip(PixelFormat::Mono1, &colors);
+        assert_eq!(bytes, [0b1011_0000, 0b1000_0000]);
+        let white = colors.map(|color| if color & 1 != 0 { 0xffffff } else { 0 });
+        assert_eq!(pixels, white);
+        // Rows start on a new byte.
+        let display = DisplayInfo {
+            width: 9,
+            height: 3,
+            format: PixelFormat::Mono1,
+        };
+        assert_eq!((display.pitch(), display.size()), (2, 6));
+    }
+
+    // Draws on a blank indexed8 display and shows pixels as digits, with dots for 0.
+    fn draw(width: usize, height: usize, paint: impl Fn(&DisplayInfo, &mut [u8])) -> Vec<String> {
+        let display = DisplayInfo {
+            width,
+            height,
+            format: PixelFormat::Indexed8,
+        };
+        let mut framebuffer = vec![0; display.size()];
+        paint(&display, &mut framebuffer);
+        framebuffer
+            .chunks(width)
+            .map(|row| {
+                row.iter()
+                    .map(|&p| if p != 0 { (b'0' + p) as char } else { '.' })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fills_are_clipped() {
+        let rect = Rect {
+            x: -2,
+            y: 1,
+            width: 4,
+            height: 10,
+        };
+        let drawn = draw(4, 3, |display, fb| display.fill(fb, rect, 1));
+        assert_eq!(drawn, ["....", "11..", "11.."]);
+        let huge = Rect {
+            x: i64::MAX - 1,
+            y: i64::MIN,
+            width: i64::MAX,
+            height: -5,
+        };
+        let drawn = draw(4, 3, |display, fb| display.fill(fb, huge, 1));
+        assert_eq!(drawn, ["....", "....", "...."]);
+    }
+
+    #[test]
+    fn lines() {
+        let drawn = draw(5, 3, |display, fb| display.line(fb, 0, 0, 4, 2, 1));
+        assert_eq!(drawn, ["1....", ".11..", "...11"]);
+        // Only the part on the display is drawn, even from far away.
+        let drawn = draw(5, 3, |display, fb| {
+            display.line(fb, i64::MIN, 1, i64::MAX, 1, 1);
+            display.line(fb, 2, -100, 2, 100, 2);
+        });
+        assert_eq!(drawn, ["..2..", "11211", "..2.."]);
+    }
+
+    #[test]
+    fn blits_skip_the_key() {
+        let sprite = [1, 0, 1, 0, 2, 0];
+        let rect = Rect {
+            x: 2,
+            y: -1,
+            width: 3,
+            height: 2,
+        };
+        let everywhere = Rect {
+            x: 0,
+            y: 0,
+            width: 4,
+            height: 2,
+        };
+        let blit = |key| {
+            draw(4, 2, |display, fb| {
+                display.fill(fb, everywhere, 3);
+                display.blit(fb, &sprite, rect, key);
+            })
+        };
+        assert_eq!(blit(1), ["33.2", "3333"]);
+        assert_eq!(blit(0), ["3332", "3333"]);
+    }
+
+    #[test]
+    fn text() {
+        let drawn = draw(8, 9, |display, fb| display.text(fb, b"1a\n-", 0, 0, 1));
+        let expected = [
+            ".1...1..", "11..1.1.", ".1..111.", ".1..1.1.", "111.1.1.", "........", "........",
+            "........", "111.....",
+        ];
+        assert_eq!(drawn, expected);
+        // Characters partly off the display are cut off.
+        let drawn = draw(4, 3, |display, fb| display.text(fb, b"11", -1, -2, 1));
+        assert_eq!(drawn, ["1...", "1...", "11.1"]);
+    }
+}
@@ -1,20 +1,428 @@
 use crate::Instruction;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
 
-use super::parser::ASTNode;
+use super::diagnostic::{Diagnostic, Source, Span};
+use super::ir::{Function, Op, Program, Terminator, RODATA_END};
 
-pub fn emit(root_node: ASTNode) -> Vec<u8> {
-    let mut instrs = Vec::new();
-    root_node.emit(&mut instrs);
-    instrs.push(Instruction::Push as u8);
-    instrs.append(&mut (1 as i64).to_le_bytes().to_vec());
-    // instrs.push(Instruction::Push as u8);
-    // instrs.append(&mut (1 as i64).to_le_bytes().to_vec());
-    // instrs.push(Instruction::Push as u8);
-    // instrs.append(&mut (0xbf9d3d as i64).to_le_bytes().to_vec());
-    // instrs.push(Instruction::Interrupt as u8);
-    // instrs.append(&mut (1 as i64).to_le_bytes().to_vec());
-    // instrs.push(Instruction::Push as u8);
-    // instrs.append(&mut (0 as i64).to_le_bytes().to_vec());
-    // instrs.push(Instruction::Jump as u8);
-    instrs
+struct InstrValuePair {
+    instr: Instruction,
+    value: Option<i64>,
+    symbol: Option<String>,
+    // A block of the same function whose address is the value.
+    block: Option<usize>,
+}
+impl InstrValuePair {
+    fn new(instr: Instruction) -> InstrValuePair {
+        InstrValuePair {
+            instr,
+            value: None,
+            symbol: None,
+            block: None,
+        }
+    }
+    fn with_value(instr: Instruction, value: i64) -> InstrValuePair {
+        InstrValuePair {
+            value: Some(value),
+            ..InstrValuePair::new(instr)
+        }
+    }
+    fn with_symbol(symbol: &str) -> InstrValuePair {
+        InstrValuePair {
+            symbol: Some(symbol.to_string()),
+            ..InstrValuePair::new(Instruction::Push)
+        }
+    }
+    fn with_block(block: usize) -> InstrValuePair {
+        InstrValuePair {
+            block: Some(block),
+            ..InstrValuePair::new(Instruction::Push)
+        }
+    }
+    fn process(
+        &mut self,
+        symbol_offsets: &HashMap<String, usize>,
+        block_offsets: &[usize],
+    ) -> Result<(), Diagnostic> {
+        if let Some(symbol) = &self.symbol {
+            match symbol_offsets.get(symbol) {
+                Some(offset) => self.value = Some(*offset as i64),
+                None => {
+                    let message = format!("undefined reference to {}", symbol);
+                    return Err(Diagnostic::error(None, message));
+                }
+            }
+        }
+        if let Some(block) = self.block {
+            self.value = Some(block_offsets[block] as i64);
+        }
+        Ok(())
+    }
+    fn size(&self) -> usize {
+        1 + self.instr.operand_size()
+    }
+    // The instruction in the syntax `asm` reads, with labels for addresses.
+    fn assembly(&self, function: &str) -> String {
+        let mnemonic = self.instr.mnemonic();
+        if let Some(symbol) = &self.symbol {
+            format!("{} {}", mnemonic, symbol)
+        } else if let Some(block) = self.block {
+            format!("{} {}", mnemonic, block_label(function, block))
+        } else if self.instr.operand_size() > 0 {
+            format!("{} {}", mnemonic, self.value.unwrap_or(0))
+        } else {
+            mnemonic.to_string()
+        }
+    }
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut vec = vec![self.instr as u8];
+        let value = self.value.unwrap_or(0).to_le_bytes();
+        vec.extend_from_slice(&value[..self.instr.operand_size()]);
+        vec
+    }
+}
+// The instructions of one function, with branch targets still symbolic.
+struct CompiledFunction {
+    name: String,
+    pairs: Vec<InstrValuePair>,
+    // Index into `pairs` of the first instruction of each block.
+    blocks: Vec<usize>,
+    // Index into `pairs` of the first instruction of each statement.
+    locations: Vec<(usize, Span)>,
+    // Index into `pairs` of each label from inline assembly.
+    labels: Vec<(String, usize)>,
+}
+impl CompiledFunction {
+    fn new(function: &Function, optimize: bool) -> CompiledFunction {
+        let mut pairs = Vec::new();
+        // The entry stub runs in the frame the VM starts with, at the top of memory.
+        if !function.name.is_empty() {
+            pairs.push(InstrValuePair::with_value(
+                Instruction::Enter,
+                function.frame_size,
+            ));
+        }
+        let mut blocks = Vec::new();
+        let mut locations = Vec::new();
+        let mut labels = Vec::new();
+        for (id, block) in function.blocks.iter().enumerate() {
+            let mut start = pairs.len();
+            blocks.push(start);
+            for op in &block.ops {
+                match op {
+                    // Statements keep their own instructions, so they still line up with the source.
+                    Op::Location(span) => locations.push((pairs.len(), *span)),
+                    Op::Label(symbol) => labels.push((symbol.clone(), pairs.len())),
+                    _ => {
+                        lower(op, &mut pairs);
+                        while optimize && peephole(&mut pairs, start) {}
+                    }
+                }
+                // Inline assembly is left exactly as written.
+                if matches!(op, Op::Location(_) | Op::Label(_) | Op::Instruction(..)) {
+                    start = pairs.len();
+                }
+            }
+            // A jump to the block laid out next is left out.
+            let next = id + 1;
+            match block.term {
+                Terminator::Jump(target) if target == next => {}
+                Terminator::Jump(target) => {
+                    pairs.push(InstrValuePair::with_block(target));
+                    pairs.push(InstrValuePair::new(Instruction::Jump));
+                }
+                Terminator::Branch { nonzero, zero } => {
+                    pairs.push(InstrValuePair::with_block(zero));
+                    pairs.push(InstrValuePair::new(Instruction::JumpZero));
+                    if nonzero != next {
+                        pairs.push(InstrValuePair::with_block(nonzero));
+                        pairs.push(InstrValuePair::new(Instruction::Jump));
+                    }
+                }
+                Terminator::Return => pairs.push(InstrValuePair::new(Instruction::Ret)),
+                Terminator::Exit => pairs.push(InstrValuePair::new(Instruction::Exit)),
+            }
+        }
+        CompiledFunction {
+            name: function.name.clone(),
+            pairs,
+            blocks,
+            locations,
+            labels,
+        }
+    }
+    fn size(&self) -> usize {
+        self.pairs.iter().map(|pair| pair.size()).sum()
+    }
+    // The address of each instruction, and of the end, if the function starts at `base`.
+    fn offsets(&self, base: usize) -> Vec<usize> {
+        let mut offsets = Vec::with_capacity(self.pairs.len() + 1);
+        let mut offset = base;
+        for pair in &self.pairs {
+            offsets.push(offset);
+            offset += pair.size();
+        }
+        offsets.push(offset);
+        offsets
+    }
+    fn block_offsets(&self, base: usize) -> Vec<usize> {
+        let offsets = self.offsets(base);
+        self.blocks.iter().map(|index| offsets[*index]).collect()
+    }
+}
+fn lower(op: &Op, pairs: &mut Vec<InstrValuePair>) {
+    let instrs: &[Instruction] = match op {
+        Op::Push(value) => {
+            return pairs.push(InstrValuePair::with_value(Instruction::Push, *value))
+        }
+        Op::Address(symbol) => return pairs.push(InstrValuePair::with_symbol(symbol)),
+        Op::SignExtend(size) => {
+            let pair = InstrValuePair::with_value(Instruction::SignExtend, *size as i64);
+            return pairs.push(pair);
+        }
+        Op::Interrupt(number) => {
+            let pair = InstrValuePair::with_value(Instruction::Interrupt, *number as i64);
+            return pairs.push(pair);
+        }
+        Op::ZeroExtend(size) => {
+            let pair = InstrValuePair::with_value(Instruction::ZeroExtend, *size as i64);
+            return pairs.push(pair);
+        }
+        Op::Neg => {
+            pairs.push(InstrValuePair::with_value(Instruction::Push, 0));
+            &[Instruction::Sub]
+        }
+        Op::FramePointer => &[Instruction::FramePointer],
+        Op::Pop => &[Instruction::Pop],
+        Op::Dupe => &[Instruction::Dupe],
+        Op::Swap => &[Instruction::Swap],
+        Op::Add => &[Instruction::Add],
+        // The instructions compute top OP second.
+        Op::Sub => &[Instruction::Swap, Instruction::Sub],
+        Op::Mul => &[Instruction::Mul],
+        Op::Div => &[Instruction::Swap, Instruction::Div],
+        Op::DivU => &[Instruction::Swap, Instruction::DivU],
+        Op::Eq => &[Instruction::Eq],
+        Op::Lt => &[Instruction::Lt],
+        Op::LtU => &[Instruction::LtU],
+        Op::Load { size, signed } => match (size, signed) {
+            (1, true) => &[Instruction::LoadI8],
+            (1, false) => &[Instruction::LoadU8],
+            (2, true) => &[Instruction::LoadI16],
+            (2, false) => &[Instruction::LoadU16],
+            (4, true) => &[Instruction::LoadI32],
+            (4, false) => &[Instruction::LoadU32],
+            _ => &[Instruction::LoadI64],
+        },
+        Op::Store { size } => match size {
+            1 => &[Instruction::StoreU8],
+            2 => &[Instruction::StoreU16],
+            4 => &[Instruction::StoreU32],
+            _ => &[Instruction::StoreI64],
+        },
+        Op::Instruction(instr, value) => {
+            return pairs.push(InstrValuePair::with_value(*instr, *value))
+        }
+        Op::Call => &[Instruction::Call],
+        Op::Location(_) | Op::Label(_) => &[],
+    };
+    pairs.extend(instrs.iter().map(|instr| InstrValuePair::new(*instr)));
+}
+// Removes instructions at the end of `pairs` that cancel out, without looking
+// before `start` since a jump may land there.
+fn peephole(pairs: &mut Vec<InstrValuePair>, start: usize) -> bool {
+    let removed = match &pairs[start..] {
+        [.., a, b] if a.instr == Instruction::Swap && b.instr == Instruction::Swap => 2,
+        [.., a, b] if a.instr == Instruction::Push && b.instr == Instruction::Pop => 2,
+        [.., a, b] if a.instr == Instruction::Dupe && b.instr == Instruction::Pop => 2,
+        [.., a, b]
+            if a.instr == Instruction::Push
+                && a.value == Some(0)
+                && a.symbol.is_none()
+                && a.block.is_none()
+                && b.instr == Instruction::Add =>
+        {
+            2
+        }
+        _ => return false,
+    };
+    pairs.truncate(pairs.len() - removed);
+    true
+}
+
+// Returns None if there were errors, which are added to `diagnostics`.
+pub fn emit(
+    program: &Program,
+    optimize: bool,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Option<Vec<u8>> {
+    let mut functions: Vec<_> = program
+        .functions
+        .iter()
+        .map(|function| CompiledFunction::new(function, optimize))
+        .collect();
+    let mut symbol_offsets = HashMap::new();
+    let mut offsets = Vec::new();
+    let mut offset = 0;
+    for function in &functions {
+        if !function.name.is_empty() {
+            symbol_offsets.insert(function.name.clone(), offset);
+        }
+        let label_offsets = function.offsets(offset);
+        for (symbol, index) in &function.labels {
+            symbol_offsets.insert(symbol.clone(), label_offsets[*index]);
+        }
+        offsets.push(offset);
+        offset += function.size();
+    }
+    // String literals come right after the code, then initialized data
+    // as part of the image, then zeroed data.
+    let code_size = offset;
+    let mut data = Vec::new();
+    for (index, string) in program.strings.iter().enumerate() {
+        symbol_offsets.insert(format!(".str{}", index), offset);
+        data.extend_from_slice(string);
+        data.push(0);
+        offset += string.len() + 1;
+    }
+    symbol_offsets.insert(RODATA_END.to_string(), offset);
+    let (initialized, zeroed): (Vec<_>, Vec<_>) =
+        program.data.iter().partition(|item| item.init.is_some());
+    for item in initialized.iter().chain(zeroed.iter()) {
+        offset = offset.div_ceil(item.align) * item.align;
+        symbol_offsets.insert(item.symbol.clone(), offset);
+        if let Some(init) = &item.init {
+            data.resize(offset - code_size, 0);
+            data.extend_from_slice(init);
+        }
+        offset += item.size;
+    }
+    if offset > 0x8000 {
+        diagnostics.push(Diagnostic::error(
+            None,
+            "program does not fit below the framebuffer at 0x8000".to_string(),
+        ));
+        return None;
+    }
+    for item in &initialized {
+        let base = symbol_offsets[&item.symbol] - code_size;
+        for (offset, symbol) in &item.relocations {
+            let address = symbol_offsets[symbol] as i64;
+            data[base + offset..base + offset + 8].copy_from_slice(&address.to_le_bytes());
+        }
+    }
+    let mut instrs = Vec::with_capacity(offset);
+    for (function, base) in functions.iter_mut().zip(offsets) {
+        let block_offsets = function.block_offsets(base);
+        for pair in function.pairs.iter_mut() {
+            // Lowering already reported undefined symbols.
+            if let Err(diagnostic) = pair.process(&symbol_offsets, &block_offsets) {
+                diagnostics.push(diagnostic);
+                return None;
+            }
+            instrs.append(&mut pair.to_bytes());
+        }
+    }
+    instrs.append(&mut data);
+    Some(instrs)
+}
+
+fn block_label(function: &str, block: usize) -> String {
+    format!(".L{}.{}", function, block)
+}
+
+// Data as `.byte` lines, with `.quad` for the addresses in `relocations`.
+fn write_bytes(out: &mut String, bytes: &[u8], relocations: &[(usize, String)]) {
+    let mut i = 0;
+    while i < bytes.len() {
+        if let Some((_, symbol)) = relocations.iter().find(|(offset, _)| *offset == i) {
+            writeln!(out, "    .quad {}", symbol).unwrap();
+            i += 8;
+            continue;
+        }
+        let end = relocations
+            .iter()
+            .map(|(offset, _)| *offset)
+            .filter(|offset| *offset > i)
+            .min()
+            .unwrap_or(bytes.len())
+            .min(i + 16);
+        let line: Vec<String> = bytes[i..end].iter().map(|byte| byte.to_string()).collect();
+        writeln!(out, "    .byte {}", line.join(", ")).unwrap();
+        i = end;
+    }
+}
+
+// The program as assembly for `asm`, which turns it into the same bytes `emit` would.
+pub fn assembly(program: &Program, optimize: bool, source: &Source) -> String {
+    let mut out = String::new();
+    for function in &program.functions {
+        let function = CompiledFunction::new(function, optimize);
+        let name = &function.name;
+        let targets: HashSet<usize> = function
+            .pairs
+            .iter()
+            .filter_map(|pair| pair.block)
+            .collect();
+        if name.is_empty() {
+            writeln!(out, "; entry").unwrap();
+        } else {
+            writeln!(out, "\n{}:", name).unwrap();
+        }
+        let mut locations = function.locations.iter().peekable();
+        let mut last = None;
+        for (i, pair) in function.pairs.iter().enumerate() {
+            for (block, _) in function
+                .blocks
+                .iter()
+                .enumerate()
+                .filter(|(_, start)| **start == i)
+            {
+                if targets.contains(&block) {
+                    writeln!(out, "{}:", block_label(name, block)).unwrap();
+                }
+            }
+            for (symbol, _) in function.labels.iter().filter(|(_, index)| *index == i) {
+                writeln!(out, "{}:", symbol).unwrap();
+            }
+            while let Some((_, span)) = locations.next_if(|(index, _)| *index == i) {
+                let Some((path, number, line)) = source.location(span.start) else {
+                    continue;
+                };
+                // One comment per source line, even if it holds several statements.
+                if last != Some((path, number)) {
+                    writeln!(out, "    ; {}:{}: {}", path.display(), number, line.trim()).unwrap();
+                    last = Some((path, number));
+                }
+            }
+            writeln!(out, "    {}", pair.assembly(name)).unwrap();
+        }
+    }
+    for (index, string) in program.strings.iter().enumerate() {
+        writeln!(out, ".str{}:", index).unwrap();
+        let mut bytes = string.clone();
+        bytes.push(0);
+        write_bytes(&mut out, &bytes, &[]);
+    }
+    writeln!(out, "{}:", RODATA_END).unwrap();
+    let (initialized, zeroed): (Vec<_>, Vec<_>) =
+        program.data.iter().partition(|item| item.init.is_some());
+    for item in &initialized {
+        if item.align > 1 {
+            writeln!(out, "    .align {}", item.align).unwrap();
+        }
+        writeln!(out, "{}:", item.symbol).unwrap();
+        write_bytes(&mut out, item.init.as_ref().unwrap(), &item.relocations);
+    }
+    if !zeroed.is_empty() {
+        writeln!(out, "    .bss").unwrap();
+    }
+    for item in &zeroed {
+        if item.align > 1 {
+            writeln!(out, "    .align {}", item.align).unwrap();
+        }
+        writeln!(out, "{}:", item.symbol).unwrap();
+        writeln!(out, "    .space {}", item.size).unwrap();
+    }
+    out
 }
@@ -0,0 +1,101 @@
+// A 3x5 pixel font for printable ASCII, drawn by the draw text interrupt. Each glyph
+// is five rows of three bits, top row first with the leftmost pixel in the high bit.
+// Lowercase letters are drawn as capitals.
+
+pub const GLYPH_WIDTH: usize = 3;
+pub const GLYPH_HEIGHT: usize = 5;
+
+// Space to backtick, then the four characters after lowercase z.
+const GLYPHS: [u16; 69] = [
+    0b000_000_000_000_000, // space
+    0b010_010_010_000_010, // !
+    0b101_101_000_000_000, // "
+    0b101_111_101_111_101, // #
+    0b011_110_010_011_110, // $
+    0b101_001_010_100_101, // %
+    0b010_101_010_101_011, // &
+    0b010_010_000_000_000, // '
+    0b001_010_010_010_001, // (
+    0b100_010_010_010_100, // )
+    0b000_101_010_101_000, // *
+    0b000_010_111_010_000, // +
+    0b000_000_000_010_100, // ,
+    0b000_000_111_000_000, // -
+    0b000_000_000_000_010, // .
+    0b001_001_010_100_100, // /
+    0b111_101_101_101_111, // 0
+    0b010_110_010_010_111, // 1
+    0b111_001_111_100_111, // 2
+    0b111_001_011_001_111, // 3
+    0b101_101_111_001_001, // 4
+    0b111_100_111_001_111, // 5
+    0b111_100_111_101_111, // 6
+    0b111_001_001_010_010, // 7
+    0b111_101_111_101_111, // 8
+    0b111_101_111_001_111, // 9
+    0b000_010_000_010_000, // :
+    0b000_010_000_010_100, // ;
+    0b001_010_100_010_001, // <
+    0b000_111_000_111_000, // =
+    0b100_010_001_010_100, // >
+    0b111_001_011_000_010, // ?
+    0b011_101_111_100_011, // @
+    0b010_101_111_101_101, // A
+    0b110_101_110_101_110, // B
+    0b011_100_100_100_011, // C
+    0b110_101_101_101_110, // D
+    0b111_100_110_100_111, // E
+    0b111_100_110_100_100, // F
+    0b011_100_101_101_011, // G
+    0b101_101_111_101_101, // H
+    0b111_010_010_010_111, // I
+    0b001_001_001_101_010, // J
+    0b101_101_110_101_101, // K
+    0b100_100_100_100_111, // L
+    0b101_111_111_101_101, // M
+    0b110_101_101_101_101, // N
+    0b010_101_101_101_010, // O
+    0b110_101_110_100_100, // P
+    0b010_101_101_110_011, // Q
+    0b110_101_110_101_101, // R
+    0b011_100_010_001_110, // S
+    0b111_010_010_010_010, // T
+    0b101_101_101_101_111, // U
+    0b101_101_101_101_010, // V
+    0b101_101_111_111_101, // W
+    0b101_101_010_101_101, // X
+    0b101_101_010_010_010, // Y
+    0b111_001_010_100_111, // Z
+    0b110_100_100_100_110, // [
+    0b100_100_010_001_001, // backslash
+    0b011_001_001_001_011, // ]
+    0b010_101_000_000_000, // ^
+    0b000_000_000_000_111, // _
+    0b100_010_000_000_000, // `
+    0b011_010_110_010_011, // {
+    0b010_010_010_010_010, // |
+    0b110_010_011_010_110, // }
+    0b000_001_111_100_000, // ~
+];
+
+pub fn glyph(c: u8) -> u16 {
+    match c.to_ascii_uppercase() {
+        c @ b' '..=b'`' => GLYPHS[(c - b' ') as usize],
+        c @ b'{'..=b'~' => GLYPHS[(c - b'{') as usize + 65],
+        _ => glyph(b'?'),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glyphs() {
+        assert_eq!(glyph(b'a'), glyph(b'A'));
+        assert_eq!(glyph(b'~'), 0b000_001_111_100_000);
+        // Anything without a glyph of its own is drawn as a question mark.
+        assert_eq!(glyph(b'\t'), glyph(b'?'));
+        assert_eq!(glyph(0xff), glyph(b'?'));
+    }
+}
@@ -0,0 +1,195 @@
+use super::vm::VM;
+
+// A change in the keyboard or mouse, from the window or an input script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Key { code: u8, down: bool },
+    // Button 0 is the left one, 1 the right and 2 the middle.
+    Button { button: u8, down: bool },
+    // In display pixels.
+    Move { x: i32, y: i32 },
+}
+
+// Letters and digits have their ASCII code as capitals; these are the other keys,
+// named as in scripts and badvm.h.
+const KEYS: [(&str, u8); 12] = [
+    ("BACKSPACE", 8),
+    ("TAB", 9),
+    ("ENTER", 10),
+    ("ESCAPE", 27),
+    ("SPACE", 32),
+    ("LEFT", 128),
+    ("RIGHT", 129),
+    ("UP", 130),
+    ("DOWN", 131),
+    ("SHIFT", 132),
+    ("CTRL", 133),
+    ("ALT", 134),
+];
+
+pub fn key_code(name: &str) -> Option<u8> {
+    let name = name.to_ascii_uppercase();
+    match name.as_bytes() {
+        [c @ (b'A'..=b'Z' | b'0'..=b'9')] => Some(*c),
+        _ => KEYS
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, code)| *code),
+    }
+}
+
+fn button(name: &str) -> Option<u8> {
+    ["left", "right", "middle"]
+        .iter()
+        .position(|button| *button == name)
+        .map(|button| button as u8)
+}
+
+// Input for runs without a window. Each line is the number of instructions to run
+// before the event, then `down KEY`, `up KEY`, `move X Y`, `press BUTTON` or
+// `release BUTTON`. Anything after a # is a comment.
+pub struct Script {
+    events: Vec<(u64, Event)>,
+    next: usize,
+}
+impl Script {
+    pub fn parse(text: &str) -> Result<Script, String> {
+        let mut events = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let words: Vec<&str> = line.split('#').next().unwrap().split_whitespace().collect();
+            if words.is_empty() {
+                continue;
+            }
+            let event = Script::event(&words).ok_or(format!("line {}: bad event", number + 1))?;
+            events.push(event);
+        }
+        // Events for the same instruction keep the order they were written in.
+        events.sort_by_key(|(steps, _)| *steps);
+        Ok(Script { events, next: 0 })
+    }
+    fn event(words: &[&str]) -> Option<(u64, Event)> {
+        let steps = words[0].parse().ok()?;
+        let event = match words[1..] {
+            ["down", key] => Event::Key {
+                code: key_code(key)?,
+                down: true,
+            },
+            ["up", key] => Event::Key {
+                code: key_code(key)?,
+                down: false,
+            },
+            ["move", x, y] => Event::Move {
+                x: x.parse().ok()?,
+                y: y.parse().ok()?,
+            },
+            ["press", name] => Event::Button {
+                button: button(name)?,
+                down: true,
+            },
+            ["release", name] => Event::Button {
+                button: button(name)?,
+                down: false,
+            },
+            _ => return None,
+        };
+        Some((steps, event))
+    }
+    // Gives the VM the events that are due by now.
+    pub fn feed(&mut self, vm: &mut VM) {
+        while let Some(&(steps, event)) = self.events.get(self.next) {
+            if steps > vm.steps {
+                break;
+            }
+            vm.input(event);
+            self.next += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{compile, Options};
+    use crate::vm::INPUT;
+    use std::path::Path;
+
+    #[test]
+    fn key_codes() {
+        assert_eq!(key_code("w"), Some(b'W'));
+        assert_eq!(key_code("7"), Some(b'7'));
+        assert_eq!(key_code("Escape"), Some(27));
+        assert_eq!(key_code("F1"), None);
+    }
+
+    #[test]
+    fn scripts() {
+        let script =
+            Script::parse("# comment\n20 up a\n10 move 3 -4 # aim\n\n20 press middle\n").unwrap();
+        let events = [
+            (10, Event::Move { x: 3, y: -4 }),
+            (
+                20,
+                Event::Key {
+                    code: b'A',
+                    down: false,
+                },
+            ),
+            (
+                20,
+                Event::Button {
+                    button: 2,
+                    down: true,
+                },
+            ),
+        ];
+        assert_eq!(script.events, events);
+        for bad in [
+            "x down a",
+            "1 down",
+            "1 press thumb",
+            "1 move 1",
+            "1 up a b",
+        ] {
+            let text = format!("1 down a\n{}", bad);
+            assert_eq!(
+                Script::parse(&text).err().as_deref(),
+                Some("line 2: bad event")
+            );
+        }
+    }
+
+    #[test]
+    fn programs_see_input() {
+        let code = "#include \"badvm.h\"
+            int main(void) {
+                int seen = 0;
+                while (seen < 2) {
+                    int type = next_event();
+                    if (type == EVENT_KEY_DOWN) seen = seen + 1;
+                    if (type == EVENT_BUTTON_DOWN) seen = seen + 1;
+                }
+                return key_down('W') * 1000 + BUTTONS * 100 + EVENT_X * 10 + EVENT_Y;
+            }";
+        let compilation = compile(code.to_string(), Path::new("test.c"), &Options::default());
+        let mut vm = VM::new(compilation.output.expect("program compiles"));
+        let mut script = Script::parse("10 down w\n20 move 3 4\n30 press right").unwrap();
+        while !vm.paused {
+            assert!(vm.steps < 10_000_000, "program does not halt");
+            script.feed(&mut vm);
+            vm.step().unwrap();
+        }
+        assert_eq!(vm.exit_code, 1234);
+    }
+
+    #[test]
+    fn full_queues_drop_events() {
+        let mut vm = VM::new(Vec::new());
+        for down in (0..300).map(|i| i % 2 == 0) {
+            vm.input(Event::Key { code: b'Q', down });
+        }
+        let register = |at: usize| vm.memory[at..at + 4].to_vec();
+        // The registers still follow what was dropped.
+        assert_eq!(register(INPUT + 12), 256u32.to_le_bytes());
+        assert_eq!(vm.memory[INPUT + 32 + b'Q' as usize], 0);
+    }
+}
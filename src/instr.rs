@@ -19,4 +19,79 @@ pub enum Instruction {
     Interrupt,
     Call,
     Ret,
+    JumpZero,
+    Eq,
+    Lt,
+    LoadI64,
+    StoreI64,
+    Enter,
+    FramePointer,
+    LoadI8,
+    LoadU16,
+    LoadI16,
+    LoadU32,
+    LoadI32,
+    StoreU16,
+    StoreU32,
+    SignExtend,
+    ZeroExtend,
+    LtU,
+    DivU,
+    Exit,
+}
+impl Instruction {
+    // Number of immediate bytes that follow the opcode.
+    pub fn operand_size(self) -> usize {
+        match self {
+            Instruction::Push | Instruction::DupeAt | Instruction::Enter => 8,
+            Instruction::Interrupt | Instruction::SignExtend | Instruction::ZeroExtend => 1,
+            _ => 0,
+        }
+    }
+    // The name used for the instruction in assembly.
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Instruction::Nop => "nop",
+            Instruction::Halt => "hlt",
+            Instruction::Push => "push",
+            Instruction::Pop => "pop",
+            Instruction::Add => "add",
+            Instruction::Mul => "mul",
+            Instruction::Sub => "sub",
+            Instruction::Div => "div",
+            Instruction::Jump => "jmp",
+            Instruction::LoadU8 => "loadu8",
+            Instruction::StoreU8 => "storeu8",
+            Instruction::Swap => "swap",
+            Instruction::Dupe => "dup",
+            Instruction::DupeAt => "dupp",
+            Instruction::Interrupt => "int",
+            Instruction::Call => "call",
+            Instruction::Ret => "ret",
+            Instruction::JumpZero => "jz",
+            Instruction::Eq => "eq",
+            Instruction::Lt => "lt",
+            Instruction::LoadI64 => "loadi64",
+            Instruction::StoreI64 => "storei64",
+            Instruction::Enter => "enter",
+            Instruction::FramePointer => "fp",
+            Instruction::LoadI8 => "loadi8",
+            Instruction::LoadU16 => "loadu16",
+            Instruction::LoadI16 => "loadi16",
+            Instruction::LoadU32 => "loadu32",
+            Instruction::LoadI32 => "loadi32",
+            Instruction::StoreU16 => "storeu16",
+            Instruction::StoreU32 => "storeu32",
+            Instruction::SignExtend => "sext",
+            Instruction::ZeroExtend => "zext",
+            Instruction::LtU => "ltu",
+            Instruction::DivU => "divu",
+            Instruction::Exit => "exit",
+        }
+    }
+    pub fn from_mnemonic(name: &str) -> Option<Instruction> {
+        (0..=u8::MAX)
+            .map_while(|byte| Instruction::try_from_primitive(byte).ok())
+            .find(|instr| instr.mnemonic() == name)
+    }
 }
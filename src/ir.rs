@@ -0,0 +1,194 @@
+use super::diagnostic::Span;
+use crate::Instruction;
+use std::fmt;
+
+// The compiler lowers the AST to this before emitting bytecode. Each function is a
+// list of basic blocks of stack operations, ending in an explicit jump, branch or return.
+pub type BlockId = usize;
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Push(i64),
+    // The address of a function, global, static local or string literal.
+    Address(String),
+    FramePointer,
+    Pop,
+    Dupe,
+    Swap,
+    Add,
+    // Sub and Div compute second OP top, unlike the instructions.
+    Sub,
+    Mul,
+    Div,
+    DivU,
+    Neg,
+    Eq,
+    Lt,
+    LtU,
+    Load { size: u8, signed: bool },
+    Store { size: u8 },
+    SignExtend(u8),
+    ZeroExtend(u8),
+    // Calls the address on top of the stack, leaving the return value.
+    Call,
+    Interrupt(u8),
+    // An instruction from inline assembly, emitted as is. The value is its operand, if it takes one.
+    Instruction(Instruction, i64),
+    // A label from inline assembly. Code can jump here, so nothing is moved across it.
+    Label(String),
+    // Marks where the code for a statement starts; emits nothing.
+    Location(Span),
+}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Terminator {
+    Jump(BlockId),
+    // Pops a value and goes to `nonzero` or `zero` depending on it.
+    Branch { nonzero: BlockId, zero: BlockId },
+    // Returns the value on top of the stack.
+    Return,
+    // Ends the program, with the value on top of the stack as its exit status.
+    Exit,
+}
+impl Terminator {
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Jump(target) => vec![*target],
+            Terminator::Branch { nonzero, zero } => vec![*nonzero, *zero],
+            Terminator::Return | Terminator::Exit => Vec::new(),
+        }
+    }
+    // Replaces every target with `map(target)`.
+    pub fn retarget(&mut self, map: impl Fn(BlockId) -> BlockId) {
+        match self {
+            Terminator::Jump(target) => *target = map(*target),
+            Terminator::Branch { nonzero, zero } => {
+                *nonzero = map(*nonzero);
+                *zero = map(*zero);
+            }
+            Terminator::Return | Terminator::Exit => {}
+        }
+    }
+}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub ops: Vec<Op>,
+    pub term: Terminator,
+}
+#[derive(Debug, Clone)]
+pub struct Function {
+    // The entry stub that calls main has no name.
+    pub name: String,
+    pub frame_size: i64,
+    // The first block is the entry; the rest are in the order they are laid out.
+    pub blocks: Vec<Block>,
+}
+// A global or static local, laid out after the code.
+#[derive(Debug, Clone)]
+pub struct Data {
+    pub symbol: String,
+    pub size: usize,
+    pub align: usize,
+    // None for data that starts out zeroed.
+    pub init: Option<Vec<u8>>,
+    // Offsets within `init` that hold the address of a symbol.
+    pub relocations: Vec<(usize, String)>,
+}
+// The end of the code and string literals, which the entry point asks the VM to
+// protect with this interrupt.
+pub const RODATA_END: &str = ".rodata_end";
+pub const PROTECT_INTERRUPT: u8 = 0x0d;
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub functions: Vec<Function>,
+    // String literal N is the symbol `.strN`.
+    pub strings: Vec<Vec<u8>>,
+    pub data: Vec<Data>,
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Op::Push(value) => write!(f, "push {}", value),
+            Op::Address(symbol) => write!(f, "addr {}", symbol),
+            Op::FramePointer => write!(f, "fp"),
+            Op::Pop => write!(f, "pop"),
+            Op::Dupe => write!(f, "dup"),
+            Op::Swap => write!(f, "swap"),
+            Op::Add => write!(f, "add"),
+            Op::Sub => write!(f, "sub"),
+            Op::Mul => write!(f, "mul"),
+            Op::Div => write!(f, "div"),
+            Op::DivU => write!(f, "divu"),
+            Op::Neg => write!(f, "neg"),
+            Op::Eq => write!(f, "eq"),
+            Op::Lt => write!(f, "lt"),
+            Op::LtU => write!(f, "ltu"),
+            Op::Load { size, signed } => {
+                write!(f, "load {}{}", if *signed { "i" } else { "u" }, size * 8)
+            }
+            Op::Store { size } => write!(f, "store {}", size * 8),
+            Op::SignExtend(size) => write!(f, "sext {}", size),
+            Op::ZeroExtend(size) => write!(f, "zext {}", size),
+            Op::Call => write!(f, "call"),
+            Op::Interrupt(number) => write!(f, "int {}", number),
+            Op::Instruction(instr, _) if instr.operand_size() == 0 => {
+                write!(f, "asm {}", instr.mnemonic())
+            }
+            Op::Instruction(instr, value) => write!(f, "asm {} {}", instr.mnemonic(), value),
+            Op::Label(symbol) => write!(f, "{}:", symbol),
+            Op::Location(span) => write!(f, "; at {}", span.start),
+        }
+    }
+}
+impl fmt::Display for Terminator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Terminator::Jump(target) => write!(f, "jmp b{}", target),
+            Terminator::Branch { nonzero, zero } => write!(f, "br b{}, b{}", nonzero, zero),
+            Terminator::Return => write!(f, "ret"),
+            Terminator::Exit => write!(f, "exit"),
+        }
+    }
+}
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = if self.name.is_empty() {
+            "<entry>"
+        } else {
+            &self.name
+        };
+        writeln!(f, "fn {} (frame {}):", name, self.frame_size)?;
+        for (id, block) in self.blocks.iter().enumerate() {
+            writeln!(f, "b{}:", id)?;
+            for op in &block.ops {
+                writeln!(f, "    {}", op)?;
+            }
+            writeln!(f, "    {}", block.term)?;
+        }
+        Ok(())
+    }
+}
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for function in &self.functions {
+            writeln!(f, "{}", function)?;
+        }
+        for (index, string) in self.strings.iter().enumerate() {
+            writeln!(f, ".str{}: {:?}", index, String::from_utf8_lossy(string))?;
+        }
+        for item in &self.data {
+            write!(
+                f,
+                "{}: {} bytes, align {}",
+                item.symbol, item.size, item.align
+            )?;
+            if let Some(init) = &item.init {
+                write!(f, " = {:02x?}", init)?;
+            }
+            for (offset, symbol) in &item.relocations {
+                write!(f, ", +{} = {}", offset, symbol)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
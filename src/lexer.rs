@@ -1,83 +1,417 @@
-#[derive(Debug, PartialEq, Eq)]
+use super::diagnostic::{Diagnostic, Span};
+use std::fmt;
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Token {
     OpenBrace,
     CloseBrace,
     OpenParen,
     CloseParen,
+    OpenBracket,
+    CloseBracket,
     Semicolon,
+    Comma,
+    Dot,
+    Arrow,
+    Void,
+    Char,
+    Short,
     Int,
+    Long,
+    Signed,
+    Unsigned,
+    Struct,
+    Union,
+    Sizeof,
+    Static,
     Return,
+    If,
+    Else,
+    While,
+    Asm,
     Identifier(String),
     Integer(i64),
-    End, // Plus,
-         // Minus,
-         // Star,
-         // Slash,
-         // Bang
+    Str(Vec<u8>),
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Bang,
+    Ampersand,
+    Assign,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    End,
+}
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            Token::OpenBrace => "{",
+            Token::CloseBrace => "}",
+            Token::OpenParen => "(",
+            Token::CloseParen => ")",
+            Token::OpenBracket => "[",
+            Token::CloseBracket => "]",
+            Token::Semicolon => ";",
+            Token::Comma => ",",
+            Token::Dot => ".",
+            Token::Arrow => "->",
+            Token::Void => "void",
+            Token::Char => "char",
+            Token::Short => "short",
+            Token::Int => "int",
+            Token::Long => "long",
+            Token::Signed => "signed",
+            Token::Unsigned => "unsigned",
+            Token::Struct => "struct",
+            Token::Union => "union",
+            Token::Sizeof => "sizeof",
+            Token::Static => "static",
+            Token::Return => "return",
+            Token::If => "if",
+            Token::Else => "else",
+            Token::While => "while",
+            Token::Asm => "asm",
+            Token::Identifier(name) => return write!(f, "identifier {}", name),
+            Token::Integer(num) => return write!(f, "integer {}", num),
+            Token::Str(_) => return write!(f, "string literal"),
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Star => "*",
+            Token::Slash => "/",
+            Token::Bang => "!",
+            Token::Ampersand => "&",
+            Token::Assign => "=",
+            Token::Equal => "==",
+            Token::NotEqual => "!=",
+            Token::Less => "<",
+            Token::LessEqual => "<=",
+            Token::Greater => ">",
+            Token::GreaterEqual => ">=",
+            Token::End => return write!(f, "end of input"),
+        };
+        write!(f, "`{}`", text)
+    }
 }
 
-pub fn lex(code: String) -> Vec<Token> {
-    let mut tokens = Vec::<Token>::new();
-    let mut read_position: usize = 0;
-    while read_position < code.len() {
-        let c = code.chars().nth(read_position).unwrap();
-        if c.is_whitespace() {
-            read_position += 1;
-            continue;
+struct Lexer<'a> {
+    code: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+}
+impl Lexer<'_> {
+    // The byte offset of the next character.
+    fn offset(&mut self) -> usize {
+        self.chars.peek().map_or(self.code.len(), |(i, _)| *i)
+    }
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().map(|(_, c)| *c)
+    }
+    fn eat(&mut self, c: char) -> bool {
+        self.chars.next_if(|(_, next)| *next == c).is_some()
+    }
+    // Consumes characters while `pred` holds, returning them as a slice of the code.
+    fn take_while(&mut self, pred: impl Fn(char) -> bool) -> &str {
+        let start = self.offset();
+        while self.chars.next_if(|(_, c)| pred(*c)).is_some() {}
+        let end = self.offset();
+        &self.code[start..end]
+    }
+    // Reads one possibly escaped character of a literal, returning its bytes.
+    fn literal_char(&mut self) -> Result<Vec<u8>, String> {
+        let c = match self.chars.next_if(|(_, c)| *c != '\n') {
+            Some((_, c)) => c,
+            None => return Err("unterminated literal".to_string()),
+        };
+        if c != '\\' {
+            return Ok(c.to_string().into_bytes());
         }
-        match c {
-            '{' => {
-                tokens.push(Token::OpenBrace);
+        let c = match self.chars.next() {
+            Some((_, c)) => c,
+            None => return Err("unterminated literal".to_string()),
+        };
+        let byte = match c {
+            'n' => b'\n',
+            't' => b'\t',
+            'r' => b'\r',
+            'a' => 0x07,
+            'b' => 0x08,
+            'f' => 0x0c,
+            'v' => 0x0b,
+            '\\' | '\'' | '"' | '?' => c as u8,
+            'x' => {
+                let digits = self.take_while(|d| d.is_ascii_hexdigit());
+                match u32::from_str_radix(digits, 16) {
+                    Ok(value) if value <= 0xff => value as u8,
+                    _ => return Err(format!("invalid escape sequence \\x{}", digits)),
+                }
             }
-            '}' => {
-                tokens.push(Token::CloseBrace);
+            '0'..='7' => {
+                let mut value = c.to_digit(8).unwrap();
+                // At most three octal digits.
+                for _ in 0..2 {
+                    match self.peek().and_then(|d| d.to_digit(8)) {
+                        Some(digit) => {
+                            value = value * 8 + digit;
+                            self.chars.next();
+                        }
+                        None => break,
+                    }
+                }
+                if value > 0xff {
+                    return Err(format!("octal escape sequence \\{:o} out of range", value));
+                }
+                value as u8
             }
-            '(' => {
-                tokens.push(Token::OpenParen);
+            _ => return Err(format!("invalid escape sequence \\{}", c)),
+        };
+        Ok(vec![byte])
+    }
+    // Skips the rest of a broken literal, up to its closing `quote` if it is on this line.
+    fn skip_literal(&mut self, quote: char) {
+        while let Some((_, c)) = self.chars.next_if(|(_, c)| *c != '\n') {
+            if c == quote {
+                break;
             }
-            ')' => {
-                tokens.push(Token::CloseParen);
+            if c == '\\' {
+                self.chars.next_if(|(_, c)| *c != '\n');
             }
-            ';' => {
-                tokens.push(Token::Semicolon);
+        }
+    }
+    // Reads an integer literal in decimal, hex (0x), binary (0b) or octal (leading 0).
+    fn integer(&mut self, first: char) -> Result<i64, String> {
+        let start = self.offset() - 1;
+        let radix = match (first, self.peek()) {
+            ('0', Some('x' | 'X')) => 16,
+            ('0', Some('b' | 'B')) => 2,
+            ('0', _) => 8,
+            _ => 10,
+        };
+        if radix == 16 || radix == 2 {
+            self.chars.next();
+        }
+        // Take everything that could belong to the literal so bad digits and suffixes are caught.
+        let rest = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_');
+        let digits = if radix == 10 {
+            format!("{}{}", first, rest)
+        } else {
+            rest.to_string()
+        };
+        let text = &self.code[start..self.offset()];
+        if let Some(bad) = digits.chars().find(|c| !c.is_digit(radix)) {
+            return Err(match bad {
+                '0'..='9' => format!("invalid digit {} in base {} literal", bad, radix),
+                _ => format!("invalid suffix on integer literal {}", text),
+            });
+        }
+        if digits.is_empty() {
+            return match radix {
+                8 => Ok(0),
+                _ => Err(format!("integer literal {} has no digits", text)),
+            };
+        }
+        i64::from_str_radix(&digits, radix)
+            .map_err(|_| format!("integer literal {} is too large", text))
+    }
+    fn token(&mut self, c: char) -> Result<Token, String> {
+        Ok(match c {
+            '{' => Token::OpenBrace,
+            '}' => Token::CloseBrace,
+            '(' => Token::OpenParen,
+            ')' => Token::CloseParen,
+            '[' => Token::OpenBracket,
+            ']' => Token::CloseBracket,
+            ';' => Token::Semicolon,
+            ',' => Token::Comma,
+            '+' => Token::Plus,
+            '-' if self.eat('>') => Token::Arrow,
+            '-' => Token::Minus,
+            '.' => Token::Dot,
+            '*' => Token::Star,
+            '/' => Token::Slash,
+            '&' => Token::Ampersand,
+            '=' if self.eat('=') => Token::Equal,
+            '=' => Token::Assign,
+            '!' if self.eat('=') => Token::NotEqual,
+            '!' => Token::Bang,
+            '<' if self.eat('=') => Token::LessEqual,
+            '<' => Token::Less,
+            '>' if self.eat('=') => Token::GreaterEqual,
+            '>' => Token::Greater,
+            '\'' => {
+                let bytes = self
+                    .literal_char()
+                    .inspect_err(|_| self.skip_literal('\''))?;
+                if !self.eat('\'') {
+                    self.skip_literal('\'');
+                    return Err("invalid character literal".to_string());
+                }
+                if bytes.len() != 1 {
+                    return Err("character literal does not fit in a char".to_string());
+                }
+                // Plain char is signed, so '\xff' is -1.
+                Token::Integer(bytes[0] as i8 as i64)
             }
-            '0'..='9' => {
-                let mut value = String::new();
-                value.push(c);
-                while read_position < code.len() {
-                    let c = code.chars().nth(read_position).unwrap();
-                    if c.is_digit(10) {
-                        value.push(c);
-                        read_position += 1;
-                    } else {
-                        break;
-                    }
+            '"' => {
+                let mut bytes = Vec::new();
+                while !self.eat('"') {
+                    let mut char = self
+                        .literal_char()
+                        .inspect_err(|_| self.skip_literal('"'))?;
+                    bytes.append(&mut char);
                 }
-                read_position -= 1;
-                tokens.push(Token::Integer(value.parse().unwrap()));
+                Token::Str(bytes)
             }
+            '0'..='9' => Token::Integer(self.integer(c)?),
             'a'..='z' | 'A'..='Z' | '_' => {
-                let mut value = String::new();
-                while read_position < code.len() {
-                    let c = code.chars().nth(read_position).unwrap();
-                    if c.is_alphanumeric() || c == '_' {
-                        value.push(c);
-                        read_position += 1;
-                    } else {
-                        break;
-                    }
+                let start = self.offset() - 1;
+                self.take_while(|c| c.is_alphanumeric() || c == '_');
+                match &self.code[start..self.offset()] {
+                    "return" => Token::Return,
+                    "void" => Token::Void,
+                    "char" => Token::Char,
+                    "short" => Token::Short,
+                    "int" => Token::Int,
+                    "long" => Token::Long,
+                    "signed" => Token::Signed,
+                    "unsigned" => Token::Unsigned,
+                    "struct" => Token::Struct,
+                    "union" => Token::Union,
+                    "sizeof" => Token::Sizeof,
+                    "static" => Token::Static,
+                    "if" => Token::If,
+                    "else" => Token::Else,
+                    "while" => Token::While,
+                    "asm" => Token::Asm,
+                    name => Token::Identifier(name.to_string()),
                 }
-                read_position -= 1;
-                match value.as_str() {
-                    "return" => tokens.push(Token::Return),
-                    "int" => tokens.push(Token::Int),
-                    _ => tokens.push(Token::Identifier(value)),
+            }
+            _ => return Err(format!("unexpected character {:?}", c)),
+        })
+    }
+}
+
+// Spans are byte offsets into `code`; problems are added to `diagnostics`.
+pub fn lex(code: &str, diagnostics: &mut Vec<Diagnostic>) -> Vec<(Token, Span)> {
+    let mut lexer = Lexer {
+        code,
+        chars: code.char_indices().peekable(),
+    };
+    let mut tokens = Vec::new();
+    while let Some((start, c)) = lexer.chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        let result = lexer.token(c);
+        let span = Span::new(start, lexer.offset());
+        match result {
+            Ok(token) => tokens.push((token, span)),
+            Err(message) => {
+                diagnostics.push(Diagnostic::error(Some(span), message));
+                // A stand-in for a broken literal keeps the parser from reporting it again.
+                match c {
+                    '0'..='9' | '\'' => tokens.push((Token::Integer(0), span)),
+                    '"' => tokens.push((Token::Str(Vec::new()), span)),
+                    _ => {}
                 }
             }
-            _ => panic!("Invalid token {}", c),
         }
-        read_position += 1;
     }
-    tokens.push(Token::End);
+    tokens.push((Token::End, Span::new(code.len(), code.len())));
     tokens
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    // The tokens of `code` before the end, and the messages of any errors.
+    fn lex_all(code: &str) -> (Vec<Token>, Vec<String>) {
+        let mut diagnostics = Vec::new();
+        let mut tokens: Vec<Token> = lex(code, &mut diagnostics)
+            .into_iter()
+            .map(|(token, _)| token)
+            .collect();
+        assert_eq!(tokens.pop(), Some(Token::End));
+        let messages = diagnostics.into_iter().map(|d| d.message).collect();
+        (tokens, messages)
+    }
+
+    #[test]
+    fn integer_bases() {
+        let (tokens, errors) = lex_all("42 0x2a 0X2A 0b101010 052 0 9223372036854775807");
+        let values = [42, 42, 42, 42, 42, 0, i64::MAX];
+        assert_eq!(tokens, values.map(Token::Integer));
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn bad_integers() {
+        let (tokens, errors) = lex_all("9223372036854775808 09 0b2 0x 12ab");
+        assert_eq!(
+            errors,
+            [
+                "integer literal 9223372036854775808 is too large",
+                "invalid digit 9 in base 8 literal",
+                "invalid digit 2 in base 2 literal",
+                "integer literal 0x has no digits",
+                "invalid suffix on integer literal 12ab",
+            ]
+        );
+        // Each is replaced by a 0 so the parser does not report it again.
+        assert_eq!(tokens, vec![Token::Integer(0); 5]);
+    }
+
+    #[test]
+    fn literals() {
+        let (tokens, errors) = lex_all(r#"'a' '\n' '\xff' '\101' "a\tb\0""#);
+        assert!(errors.is_empty());
+        assert_eq!(
+            tokens,
+            [
+                Token::Integer(97),
+                Token::Integer(10),
+                Token::Integer(-1),
+                Token::Integer(65),
+                Token::Str(b"a\tb\0".to_vec()),
+            ]
+        );
+        // The rest of a broken literal is skipped, so nothing else is reported.
+        let (tokens, errors) = lex_all(r#"'ab' "\q\"" '\777' x"#);
+        assert_eq!(
+            errors,
+            [
+                "invalid character literal",
+                "invalid escape sequence \\q",
+                "octal escape sequence \\777 out of range"
+            ]
+        );
+        assert_eq!(
+            tokens,
+            [
+                Token::Integer(0),
+                Token::Str(Vec::new()),
+                Token::Integer(0),
+                Token::Identifier("x".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn large_inputs_lex_in_linear_time() {
+        // Looking characters up by index from the start, as lexers here once did,
+        // takes hours on this.
+        let code = "x = 0x1f + 'c';\n".repeat(200_000);
+        let start = Instant::now();
+        let (tokens, errors) = lex_all(&code);
+        assert!(errors.is_empty());
+        assert_eq!(tokens.len(), 6 * 200_000);
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,160 @@
+use super::ir::{Op, Program};
+use std::collections::{HashMap, HashSet};
+
+// The functions and data a library defines.
+pub fn exports(library: &Program) -> HashSet<String> {
+    library
+        .functions
+        .iter()
+        .map(|function| function.name.clone())
+        .chain(library.data.iter().map(|item| item.symbol.clone()))
+        .collect()
+}
+
+// Copies whatever `program` uses from `library` into it, along with what that
+// uses in turn. Anything the program defines itself is left alone.
+pub fn link(program: &mut Program, library: &Program) {
+    let mut defined: HashSet<String> = program
+        .functions
+        .iter()
+        .map(|function| function.name.clone())
+        .chain(program.data.iter().map(|item| item.symbol.clone()))
+        .collect();
+    let mut pending: Vec<String> = program
+        .functions
+        .iter()
+        .flat_map(|function| &function.blocks)
+        .flat_map(|block| &block.ops)
+        .filter_map(|op| match op {
+            Op::Address(symbol) => Some(symbol.clone()),
+            _ => None,
+        })
+        .chain(
+            program
+                .data
+                .iter()
+                .flat_map(|item| item.relocations.iter().map(|(_, symbol)| symbol.clone())),
+        )
+        .collect();
+    // The library's string literals are numbered after the program's as they are used.
+    let mut strings = HashMap::new();
+    let mut rename = |symbol: &mut String, program: &mut Program| {
+        let Some(index) = symbol.strip_prefix(".str") else {
+            return;
+        };
+        let index: usize = index.parse().unwrap();
+        *symbol = strings
+            .entry(index)
+            .or_insert_with(|| {
+                program.strings.push(library.strings[index].clone());
+                format!(".str{}", program.strings.len() - 1)
+            })
+            .clone();
+    };
+    while let Some(symbol) = pending.pop() {
+        if !defined.insert(symbol.clone()) {
+            continue;
+        }
+        if let Some(function) = library.functions.iter().find(|f| f.name == symbol) {
+            let mut function = function.clone();
+            for block in &mut function.blocks {
+                // Locations point into the library's source, not the program's.
+                block.ops.retain(|op| !matches!(op, Op::Location(_)));
+                for op in &mut block.ops {
+                    if let Op::Address(target) = op {
+                        rename(target, program);
+                        pending.push(target.clone());
+                    }
+                }
+            }
+            program.functions.push(function);
+        } else if let Some(item) = library.data.iter().find(|item| item.symbol == symbol) {
+            let mut item = item.clone();
+            for (_, target) in &mut item.relocations {
+                rename(target, program);
+                pending.push(target.clone());
+            }
+            program.data.push(item);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::{compile, tests::run, Emit, Options};
+    use std::path::Path;
+
+    // The functions and data in the linked program, from its IR.
+    fn symbols(code: &str) -> Vec<String> {
+        let options = Options {
+            emit: Emit::Ir,
+            ..Default::default()
+        };
+        let compilation = compile(code.to_string(), Path::new("test.c"), &options);
+        let ir = String::from_utf8(compilation.output.expect("program compiles")).unwrap();
+        ir.lines()
+            .filter_map(|line| match line.strip_prefix("fn ") {
+                Some(function) => function.split(' ').next(),
+                None => line
+                    .split_once(": ")
+                    .filter(|(_, data)| data.contains(" bytes, align "))
+                    .map(|(symbol, _)| symbol),
+            })
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn runtime_functions_run() {
+        let code = r#"
+            #include "stdlib.h"
+            #include "string.h"
+            int same(char *a, char *b) {
+                while (*a == *b) {
+                    if (*a == 0) return 1;
+                    a = a + 1;
+                    b = b + 1;
+                }
+                return 0;
+            }
+            int main(void) {
+                char buf[40];
+                if (!same(itoa(-1234, buf, 10), "-1234")) return 1;
+                if (!same(itoa(255, buf, 16), "ff")) return 2;
+                if (!same(itoa(5, buf, 2), "101")) return 3;
+                if (!same(itoa(0, buf, 10), "0")) return 4;
+                if (strlen(itoa(7, buf, 1)) != 0) return 5;
+                if (abs(-7) != 7) return 6;
+                if (abs(7) != 7) return 7;
+                if (min(3, -4) != -4) return 8;
+                if (max(3, -4) != 3) return 9;
+                memset(buf, 'x', 10);
+                buf[10] = 0;
+                return strlen(buf);
+            }"#;
+        let vm = run(code, &Options::default()).expect("program runs");
+        assert_eq!(vm.exit_code, 10);
+    }
+
+    #[test]
+    fn only_what_is_used_is_linked() {
+        assert_eq!(symbols("int main(void) { return 3; }"), ["<entry>", "main"]);
+        let code = r#"
+            #include "stdlib.h"
+            int main(void) { return rand() + abs(-1); }"#;
+        let mut linked = symbols(code);
+        linked.sort();
+        assert_eq!(linked, ["<entry>", "__rand_state", "abs", "main", "rand"]);
+    }
+
+    #[test]
+    fn programs_can_replace_runtime_functions() {
+        let code = r#"
+            #include "stdlib.h"
+            int abs(int n) { return 42; }
+            int main(void) { return abs(-1); }"#;
+        assert_eq!(symbols(code), ["<entry>", "abs", "main"]);
+        let vm = run(code, &Options::default()).expect("program runs");
+        assert_eq!(vm.exit_code, 42);
+    }
+}
@@ -4,38 +4,200 @@ extern crate minifb;
 
 use std::fs::File;
 use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::channel;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use std::thread::sleep;
 use std::{env, thread};
 
 use coarsetime::{Duration, Instant, Updater};
 
 use getopts::Options;
-use minifb::{Key, Window, WindowOptions};
-const WIDTH: usize = 64;
-const HEIGHT: usize = 64;
+use minifb::{Key, KeyRepeat, MouseButton, MouseMode, Scale, Window, WindowOptions};
+mod display;
+mod font;
+mod input;
 mod vm;
-use vm::VM;
+use vm::{VMError, VM};
 mod asm;
-use crate::compiler::compile;
-use crate::vm::DisplayInfo;
+mod capture;
+mod clock;
+use crate::clock::Clock;
+use crate::compiler::{compile, Emit};
+use crate::display::{DisplayInfo, PixelFormat};
+use crate::input::{key_code, Event, Script};
 use asm::assemble;
 mod compiler;
 #[path = "instr.rs"]
 pub mod instr;
 use instr::Instruction;
 
+fn open_window(display: &DisplayInfo, scale: Scale) -> minifb::Result<Window> {
+    Window::new(
+        "Test - ESC to exit",
+        display.width,
+        display.height,
+        WindowOptions {
+            scale,
+            ..Default::default()
+        },
+    )
+}
+
+// The VM's code for a window key, if programs can see it.
+fn window_key(key: Key) -> Option<u8> {
+    let code = key as u8;
+    match key {
+        _ if key <= Key::Key9 => Some(b'0' + code),
+        _ if key <= Key::Z => Some(b'A' + code - Key::A as u8),
+        Key::Backspace => key_code("BACKSPACE"),
+        Key::Tab => key_code("TAB"),
+        Key::Enter => key_code("ENTER"),
+        Key::Escape => key_code("ESCAPE"),
+        Key::Space => key_code("SPACE"),
+        Key::Left => key_code("LEFT"),
+        Key::Right => key_code("RIGHT"),
+        Key::Up => key_code("UP"),
+        Key::Down => key_code("DOWN"),
+        Key::LeftShift | Key::RightShift => key_code("SHIFT"),
+        Key::LeftCtrl | Key::RightCtrl => key_code("CTRL"),
+        Key::LeftAlt | Key::RightAlt => key_code("ALT"),
+        _ => None,
+    }
+}
+
+// What changed in the keyboard and mouse since the last window update.
+fn window_input(window: &Window, mouse: &mut (i32, i32), buttons: &mut [bool; 3]) -> Vec<Event> {
+    let mut events = Vec::new();
+    let pressed = window.get_keys_pressed(KeyRepeat::No).unwrap_or_default();
+    let released = window.get_keys_released().unwrap_or_default();
+    for (keys, down) in [(pressed, true), (released, false)] {
+        for code in keys.into_iter().filter_map(window_key) {
+            events.push(Event::Key { code, down });
+        }
+    }
+    if let Some((x, y)) = window.get_mouse_pos(MouseMode::Clamp) {
+        if (x as i32, y as i32) != *mouse {
+            *mouse = (x as i32, y as i32);
+            events.push(Event::Move {
+                x: mouse.0,
+                y: mouse.1,
+            });
+        }
+    }
+    let all = [MouseButton::Left, MouseButton::Right, MouseButton::Middle];
+    for (button, (was_down, which)) in buttons.iter_mut().zip(all).enumerate() {
+        let down = window.get_mouse_down(which);
+        if down != *was_down {
+            *was_down = down;
+            events.push(Event::Button {
+                button: button as u8,
+                down,
+            });
+        }
+    }
+    events
+}
+
+// Why a run ended.
+enum Stop {
+    Halted,
+    Fault(VMError),
+    // The window was closed, or Escape pressed, while the program was running.
+    Closed,
+    // The program ran the instructions --max-steps allowed.
+    Limit,
+}
+
+impl Stop {
+    // What to tell the user, if anything, and the exit code: the program's own if it
+    // halted, 1 after a fault, 130 if the window was closed first and 0 at the step
+    // limit.
+    fn report(&self, vm: &VM) -> (Option<String>, i32) {
+        match self {
+            Stop::Halted => (None, vm.exit_code as i32),
+            Stop::Fault(err) => (Some(format!("{} after {} instructions", err, vm.steps)), 1),
+            Stop::Closed => (
+                Some(format!("Window closed after {} instructions", vm.steps)),
+                130,
+            ),
+            Stop::Limit => (Some(format!("Stopped after {} instructions", vm.steps)), 0),
+        }
+    }
+}
+
+fn finish(vm: &VM, stop: Stop) -> ! {
+    let (message, code) = stop.report(vm);
+    if let Some(message) = message {
+        eprintln!("{}", message);
+    }
+    std::process::exit(code)
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = env::args().collect();
     let mut opts = Options::new();
     match args[1].as_str() {
         "vm" => {
             opts.optflag("w", "window", "open window");
+            opts.optopt("", "width", "display width in pixels (default 64)", "N");
+            opts.optopt("", "height", "display height in pixels (default 64)", "N");
+            opts.optopt(
+                "",
+                "format",
+                "pixel format: argb32, indexed8 (default) or mono1",
+                "FORMAT",
+            );
+            opts.optopt(
+                "",
+                "scale",
+                "window pixels per display pixel: 1, 2, 4, 8 (default), 16 or 32",
+                "N",
+            );
+            opts.optopt(
+                "",
+                "capture",
+                "write the framebuffer to FILE as a PPM image when the program stops",
+                "FILE",
+            );
+            opts.optmulti(
+                "",
+                "capture-at",
+                "also capture after N instructions, to FILE-N",
+                "N",
+            );
+            opts.optflag(
+                "",
+                "capture-frames",
+                "also capture every frame finished with int 0x02, to FILE-frameN",
+            );
+            opts.optopt(
+                "",
+                "fixed-clock",
+                "let time pass at N instructions to the millisecond, for reproducible runs",
+                "N",
+            );
+            opts.optopt(
+                "",
+                "input",
+                "feed the VM the key and mouse events in FILE, timed by instruction count",
+                "FILE",
+            );
+            opts.optopt(
+                "",
+                "max-steps",
+                "stop after N instructions, for programs that never halt",
+                "N",
+            );
         }
         "asm" => {}
         "disasm" => {}
-        "cc" => {}
+        "cc" => {
+            opts.optopt("", "emit", "what to output: bin (default) or ir", "KIND");
+            opts.optflag("O", "optimize", "optimize the generated code");
+            opts.optflag("S", "", "write assembly to test.asm instead of a binary");
+        }
         _ => {
             println!("HELP MESSAGE");
             return Ok(());
@@ -45,72 +207,183 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     match args[1].as_str() {
         "vm" => {
             let mut window: Option<Window> = Option::None;
-
-            if matches.opt_present("w") {
-                window = Option::Some(Window::new(
-                    "Test - ESC to exit",
-                    WIDTH,
-                    HEIGHT,
-                    WindowOptions {
-                        scale: minifb::Scale::X8,
-                        ..Default::default()
-                    },
-                )?);
+            let capture = matches.opt_str("capture").map(PathBuf::from);
+            let capture_at = matches
+                .opt_strs("capture-at")
+                .iter()
+                .map(|n| n.parse::<u64>())
+                .collect::<Result<Vec<_>, _>>()?;
+            let capture_frames = matches.opt_present("capture-frames");
+            let max_steps = matches
+                .opt_str("max-steps")
+                .map(|n| n.parse::<u64>())
+                .transpose()?;
+            if max_steps == Some(0) {
+                return Err("--max-steps needs at least 1 instruction".into());
+            }
+            if capture.is_none() && (!capture_at.is_empty() || capture_frames) {
+                return Err("--capture-at and --capture-frames need --capture".into());
+            }
+            let mut display = DisplayInfo::default();
+            if let Some(width) = matches.opt_str("width") {
+                display.width = width.parse()?;
             }
+            if let Some(height) = matches.opt_str("height") {
+                display.height = height.parse()?;
+            }
+            if let Some(format) = matches.opt_str("format") {
+                display.format = PixelFormat::from_name(&format)
+                    .ok_or(format!("unknown pixel format {}", format))?;
+            }
+            let scale = match matches.opt_str("scale").as_deref() {
+                None | Some("8") => Scale::X8,
+                Some("1") => Scale::X1,
+                Some("2") => Scale::X2,
+                Some("4") => Scale::X4,
+                Some("16") => Scale::X16,
+                Some("32") => Scale::X32,
+                Some(scale) => return Err(format!("unsupported scale {}", scale).into()),
+            };
             let mut code: Vec<u8> = Vec::new();
             let mut file = File::open(env::current_dir()?.join("test.bin"))?;
             file.read_to_end(&mut code)?;
-            let mut vm = VM::new(
-                code,
-                vec![0; WIDTH * HEIGHT],
-                DisplayInfo {
-                    width: WIDTH,
-                    height: HEIGHT,
-                },
-            );
+            let mut vm = VM::new(code);
+            vm.set_display(display)?;
+            if let Some(rate) = matches.opt_str("fixed-clock") {
+                match rate.parse()? {
+                    0 => return Err("--fixed-clock needs at least 1 instruction".into()),
+                    rate => vm.set_clock(Clock::Fixed(rate)),
+                }
+            }
+            let mut script = match matches.opt_str("input") {
+                Some(path) => Script::parse(&std::fs::read_to_string(&path)?)
+                    .map_err(|err| format!("{}: {}", path, err))?,
+                None => Script::parse("")?,
+            };
+            if matches.opt_present("w") {
+                if capture.is_some() {
+                    return Err("--capture only works without a window".into());
+                }
+                window = Option::Some(open_window(&display, scale)?);
+            }
 
             match window {
-                None => loop {
-                    vm.step()?;
-                    if vm.paused {
-                        break;
+                None => {
+                    let stop = loop {
+                        let frames = vm.frames;
+                        script.feed(&mut vm);
+                        if let Err(err) = vm.step() {
+                            break Stop::Fault(err);
+                        }
+                        if let Some(path) = &capture {
+                            if capture_at.contains(&vm.steps) {
+                                let path = capture::tagged(path, &vm.steps.to_string());
+                                capture::write_ppm(&path, &vm.framebuffer(), &vm.display)?;
+                            }
+                            if capture_frames && vm.frames != frames {
+                                let path = capture::tagged(path, &format!("frame{}", vm.frames));
+                                capture::write_ppm(&path, &vm.framebuffer(), &vm.display)?;
+                            }
+                        }
+                        if vm.paused {
+                            break Stop::Halted;
+                        }
+                        if Some(vm.steps) == max_steps {
+                            break Stop::Limit;
+                        }
+                    };
+                    // A crashed program is captured too, to show how far it got.
+                    if let Some(path) = &capture {
+                        capture::write_ppm(path, &vm.framebuffer(), &vm.display)?;
                     }
-                },
+                    finish(&vm, stop);
+                }
                 Some(mut window) => {
-                    let (sender, reciever) = channel::<Vec<u32>>();
-                    let alive = Arc::new(Mutex::<bool>::new(true));
-                    thread::spawn(move || {
+                    let (sender, reciever) = channel::<(Vec<u32>, DisplayInfo)>();
+                    let (input, events) = channel::<Event>();
+                    // Cleared by the window to stop the VM.
+                    let running = Arc::new(AtomicBool::new(true));
+                    let vm_running = running.clone();
+                    let vm_thread = thread::spawn(move || {
                         let _updater = Updater::new(2).start().unwrap();
-                        let mut last_tick = Instant::now();
-                        loop {
-                            match vm.step() {
-                                Ok(()) => {}
-                                Err(err) => {
-                                    println!("{:?}", err);
-                                    break;
-                                }
+                        let frame_time = Duration::from_secs(1) / 60;
+                        let mut next_frame = Instant::now() + frame_time;
+                        // Sends only fail once the window has gone, which also clears running.
+                        let stop = loop {
+                            if !vm_running.load(Ordering::Relaxed) {
+                                break Stop::Closed;
+                            }
+                            script.feed(&mut vm);
+                            for event in events.try_iter() {
+                                vm.input(event);
+                            }
+                            let frames = vm.frames;
+                            if let Err(err) = vm.step() {
+                                break Stop::Fault(err);
                             }
                             if vm.paused {
-                                println!("paused {:?}", vm);
-                                break;
+                                break Stop::Halted;
                             }
-                            if last_tick.elapsed_since_recent() > Duration::from_millis(16) {
-                                let pixels = vm.framebuffer.clone();
-                                sender.send(pixels).unwrap();
-                                sleep(std::time::Duration::from_millis(16));
+                            if Some(vm.steps) == max_steps {
+                                break Stop::Limit;
                             }
-                            last_tick = Instant::recent();
-                        }
-                        println!("VM crashed");
+                            // The window shows the last frame it was sent, so programs that
+                            // finish their frames are never seen half drawn. Programs that
+                            // never do are shown as they are once a frame.
+                            if std::mem::take(&mut vm.vsync) {
+                                let _ = sender.send((vm.framebuffer(), vm.display));
+                                // Wait for the next frame; a late one starts the count again.
+                                let now = Instant::now();
+                                if next_frame > now {
+                                    sleep((next_frame - now).into());
+                                    next_frame += frame_time;
+                                } else {
+                                    next_frame = now + frame_time;
+                                }
+                            } else if (vm.frames != frames || vm.frames == 0)
+                                && Instant::recent() >= next_frame
+                            {
+                                let _ = sender.send((vm.framebuffer(), vm.display));
+                                next_frame = Instant::recent() + frame_time;
+                            }
+                        };
+                        // What the program left behind, which it may not have finished as a frame.
+                        let _ = sender.send((vm.framebuffer(), vm.display));
+                        (vm, stop)
                     });
                     // Limit to max ~60 fps update rate
                     window.limit_update_rate(Some(std::time::Duration::from_micros(16600)));
-                    while window.is_open()
-                        && !window.is_key_down(Key::Escape)
-                        && *alive.lock().unwrap()
-                    {
-                        let fb = reciever.recv()?;
-                        window.update_with_buffer(&fb, WIDTH, HEIGHT)?;
+                    let (mut mouse, mut buttons) = ((0, 0), [false; 3]);
+                    // Once the VM stops, its last frame stays up until the window is closed.
+                    while window.is_open() && !window.is_key_down(Key::Escape) {
+                        // Only the newest frame is worth showing. The VM stopping drops
+                        // the sender, after the frames it sent have been received.
+                        let newest = reciever.try_iter().last();
+                        match newest {
+                            Some((fb, frame)) => {
+                                // The program changed the resolution.
+                                if (frame.width, frame.height) != (display.width, display.height) {
+                                    display = frame;
+                                    window = open_window(&display, scale)?;
+                                    window.limit_update_rate(Some(
+                                        std::time::Duration::from_micros(16600),
+                                    ));
+                                }
+                                window.update_with_buffer(&fb, display.width, display.height)?;
+                            }
+                            // Keeps the window responding while the program works on a frame.
+                            None => window.update(),
+                        }
+                        for event in window_input(&window, &mut mouse, &mut buttons) {
+                            // The VM may have stopped already.
+                            let _ = input.send(event);
+                        }
+                    }
+                    running.store(false, Ordering::Relaxed);
+                    match vm_thread.join() {
+                        Ok((vm, stop)) => finish(&vm, stop),
+                        // The panic has been printed already.
+                        Err(_) => std::process::exit(101),
                     }
                 }
             }
@@ -128,14 +401,43 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
         }
         "cc" => {
-            let mut file = File::open(env::current_dir().unwrap().join("test.c")).unwrap();
+            let path = env::current_dir().unwrap().join("test.c");
+            let mut file = File::open(&path).unwrap();
             let mut code = String::new();
             file.read_to_string(&mut code).unwrap();
             println!("{}", code);
+            let mut options = compiler::Options {
+                optimize: matches.opt_present("O"),
+                ..Default::default()
+            };
+            if matches.opt_present("S") {
+                options.emit = Emit::Assembly;
+            }
+            match matches.opt_str("emit").as_deref() {
+                None | Some("bin") => {}
+                Some("ir") => options.emit = Emit::Ir,
+                Some(kind) => return Err(format!("unknown --emit kind {}", kind).into()),
+            }
+            let compilation = compile(code, &path, &options);
+            for diagnostic in &compilation.diagnostics {
+                eprintln!("{}", compilation.source.render(diagnostic));
+            }
+            let binary = match compilation.output {
+                Some(output) if options.emit == Emit::Ir => {
+                    print!("{}", String::from_utf8_lossy(&output));
+                    return Ok(());
+                }
+                Some(output) if options.emit == Emit::Assembly => {
+                    File::create(env::current_dir()?.join("test.asm"))?.write_all(&output)?;
+                    return Ok(());
+                }
+                Some(binary) => binary,
+                None => std::process::exit(1),
+            };
             match File::create(env::current_dir().unwrap().join("../vm/test.bin")) {
                 Err(_) => panic!("Could not create file"),
                 Ok(mut file) => {
-                    file.write_all(&compile(code)).unwrap();
+                    file.write_all(&binary).unwrap();
                 }
             }
         }
@@ -144,14 +446,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             let mut code: Vec<u8> = Vec::new();
             let mut file = File::open(env::current_dir()?.join("test.bin"))?;
             file.read_to_end(&mut code)?;
-            let vm = VM::new(
-                code,
-                vec![0; WIDTH * HEIGHT],
-                DisplayInfo {
-                    width: WIDTH,
-                    height: HEIGHT,
-                },
-            );
+            let vm = VM::new(code);
             println!("{:?}", vm);
         }
         _ => println!("Unrecognized command"),
@@ -159,3 +454,29 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stops_are_reported() {
+        let mut vm = VM::new(Vec::new());
+        vm.exit_code = 3;
+        vm.steps = 12;
+        assert_eq!(Stop::Halted.report(&vm), (None, 3));
+        let err = VMError {
+            message: "Division by zero".to_string(),
+            address: 5,
+        };
+        let fault = (
+            Some("VMError Division by zero at 0005 after 12 instructions".to_string()),
+            1,
+        );
+        assert_eq!(Stop::Fault(err).report(&vm), fault);
+        let closed = (Some("Window closed after 12 instructions".to_string()), 130);
+        assert_eq!(Stop::Closed.report(&vm), closed);
+        let limit = (Some("Stopped after 12 instructions".to_string()), 0);
+        assert_eq!(Stop::Limit.report(&vm), limit);
+    }
+}
@@ -0,0 +1,375 @@
+use super::ir::{BlockId, Function, Op, Program, Terminator};
+
+// Rewrites every function into an equivalent one with fewer ops and blocks.
+pub fn optimize(program: &mut Program) {
+    for function in &mut program.functions {
+        loop {
+            let mut changed = false;
+            for block in &mut function.blocks {
+                let ops = std::mem::take(&mut block.ops);
+                let length = ops.len();
+                for op in ops {
+                    block.ops.push(op);
+                    while fold(&mut block.ops) {}
+                }
+                changed |= block.ops.len() != length;
+                changed |= fold_branch(&mut block.ops, &mut block.term);
+            }
+            changed |= thread_jumps(function);
+            changed |= merge_blocks(function);
+            changed |= remove_unreachable(function);
+            if !changed {
+                break;
+            }
+        }
+    }
+}
+
+// Simplifies the ops at the end of `ops`, returning whether anything changed.
+// Every rule makes the block shorter, so repeating this terminates.
+fn fold(ops: &mut Vec<Op>) -> bool {
+    let n = ops.len();
+    let constant = match ops.as_slice() {
+        [.., Op::Push(a), Op::Push(b), op] => binary(op, *a, *b).map(|value| (value, 3)),
+        _ => None,
+    };
+    let constant = constant.or_else(|| match ops.as_slice() {
+        [.., Op::Push(a), op] => unary(op, *a).map(|value| (value, 2)),
+        _ => None,
+    });
+    if let Some((value, removed)) = constant {
+        ops.truncate(n - removed);
+        ops.push(Op::Push(value));
+        return true;
+    }
+    let (replacement, removed) = match ops.as_slice() {
+        // Offsets into the same object add up.
+        [.., Op::Push(a), Op::Add, Op::Push(b), Op::Add] => {
+            (vec![Op::Push(a.wrapping_add(*b)), Op::Add], 4)
+        }
+        [.., Op::Push(0), Op::Add | Op::Sub] | [.., Op::Push(1), Op::Mul | Op::Div | Op::DivU] => {
+            (Vec::new(), 2)
+        }
+        [.., Op::Swap, Op::Swap] => (Vec::new(), 2),
+        // A value that is computed and thrown away.
+        [.., Op::Push(_) | Op::Address(_) | Op::FramePointer | Op::Dupe, Op::Pop] => {
+            (Vec::new(), 2)
+        }
+        [.., Op::Neg | Op::SignExtend(_) | Op::ZeroExtend(_), Op::Pop] => (vec![Op::Pop], 2),
+        // Other loads may fault or read a device register, so they stay. The start of a
+        // global or local variable is always plain memory.
+        [.., Op::Address(_) | Op::FramePointer, Op::Load { .. }, Op::Pop] => (Vec::new(), 3),
+        _ => return false,
+    };
+    ops.truncate(n - removed);
+    ops.extend(replacement);
+    true
+}
+
+// Evaluates `a OP b` the way the VM would, unless it would trap.
+fn binary(op: &Op, a: i64, b: i64) -> Option<i64> {
+    Some(match op {
+        Op::Add => a.wrapping_add(b),
+        Op::Sub => a.wrapping_sub(b),
+        Op::Mul => a.wrapping_mul(b),
+        Op::Div => a.checked_div(b)?,
+        Op::DivU => (a as u64).checked_div(b as u64)? as i64,
+        Op::Eq => (a == b) as i64,
+        Op::Lt => (a < b) as i64,
+        Op::LtU => ((a as u64) < (b as u64)) as i64,
+        _ => return None,
+    })
+}
+
+fn unary(op: &Op, a: i64) -> Option<i64> {
+    let shift = |size: u8| 64 - (size as u32).min(8) * 8;
+    Some(match op {
+        Op::Neg => a.wrapping_neg(),
+        Op::SignExtend(size) => a.wrapping_shl(shift(*size)).wrapping_shr(shift(*size)),
+        Op::ZeroExtend(size) => {
+            ((a as u64)
+                .wrapping_shl(shift(*size))
+                .wrapping_shr(shift(*size))) as i64
+        }
+        _ => return None,
+    })
+}
+
+// Turns a branch on a known value into a jump, and drops negations of the condition.
+fn fold_branch(ops: &mut Vec<Op>, term: &mut Terminator) -> bool {
+    let Terminator::Branch { nonzero, zero } = *term else {
+        return false;
+    };
+    match ops.as_slice() {
+        [.., Op::Push(value)] => {
+            *term = Terminator::Jump(if *value != 0 { nonzero } else { zero });
+            ops.pop();
+        }
+        [.., Op::Push(0), Op::Eq] => {
+            *term = Terminator::Branch {
+                nonzero: zero,
+                zero: nonzero,
+            };
+            ops.truncate(ops.len() - 2);
+        }
+        _ if nonzero == zero => {
+            *term = Terminator::Jump(zero);
+            ops.push(Op::Pop);
+        }
+        _ => return false,
+    }
+    true
+}
+
+// Skips over blocks that do nothing but jump somewhere else.
+fn thread_jumps(function: &mut Function) -> bool {
+    let blocks = &function.blocks;
+    let forward = |target: BlockId| {
+        let mut next = target;
+        // Empty blocks jumping around in a loop are left alone.
+        for _ in 0..blocks.len() {
+            match blocks[next] {
+                ref block if !block.ops.is_empty() => return next,
+                ref block => match block.term {
+                    Terminator::Jump(after) => next = after,
+                    _ => return next,
+                },
+            }
+        }
+        target
+    };
+    let terms: Vec<Terminator> = blocks
+        .iter()
+        .map(|block| {
+            let mut term = block.term.clone();
+            term.retarget(forward);
+            // A jump to an empty block that returns or exits can do that itself.
+            if let Terminator::Jump(target) = term {
+                let next = &blocks[target];
+                if next.ops.is_empty() && matches!(next.term, Terminator::Return | Terminator::Exit)
+                {
+                    term = next.term.clone();
+                }
+            }
+            term
+        })
+        .collect();
+    let mut changed = false;
+    for (block, term) in function.blocks.iter_mut().zip(terms) {
+        changed |= block.term != term;
+        block.term = term;
+    }
+    changed
+}
+
+// Appends a block to the one before it when that is the only way to reach it.
+fn merge_blocks(function: &mut Function) -> bool {
+    let mut predecessors = vec![0; function.blocks.len()];
+    for block in &function.blocks {
+        for target in block.term.successors() {
+            predecessors[target] += 1;
+        }
+    }
+    let mut changed = false;
+    for id in 0..function.blocks.len() {
+        let Terminator::Jump(target) = function.blocks[id].term else {
+            continue;
+        };
+        if target == 0 || target == id || predecessors[target] != 1 {
+            continue;
+        }
+        let ops = std::mem::take(&mut function.blocks[target].ops);
+        // Nothing jumps to the merged block now, so it gets removed.
+        let term = std::mem::replace(&mut function.blocks[target].term, Terminator::Exit);
+        function.blocks[id].ops.extend(ops);
+        function.blocks[id].term = term;
+        changed = true;
+    }
+    changed
+}
+
+fn remove_unreachable(function: &mut Function) -> bool {
+    let mut reachable = vec![false; function.blocks.len()];
+    // Inline assembly can jump to its labels from anywhere.
+    let mut stack: Vec<BlockId> = (0..function.blocks.len())
+        .filter(|id| {
+            let ops = &function.blocks[*id].ops;
+            *id == 0 || ops.iter().any(|op| matches!(op, Op::Label(_)))
+        })
+        .collect();
+    while let Some(id) = stack.pop() {
+        if !reachable[id] {
+            reachable[id] = true;
+            stack.extend(function.blocks[id].term.successors());
+        }
+    }
+    if reachable.iter().all(|reachable| *reachable) {
+        return false;
+    }
+    let mut index = vec![0; reachable.len()];
+    let mut next = 0;
+    for (id, reachable) in reachable.iter().enumerate() {
+        if *reachable {
+            index[id] = next;
+            next += 1;
+        }
+    }
+    let blocks = std::mem::take(&mut function.blocks);
+    function.blocks = blocks
+        .into_iter()
+        .zip(reachable)
+        .filter(|(_, reachable)| *reachable)
+        .map(|(mut block, _)| {
+            block.term.retarget(|target| index[target]);
+            block
+        })
+        .collect();
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::{tests::run, Options};
+    use crate::vm::{FRAMEBUFFER, FRAMEBUFFER_SIZE};
+
+    // Runs `code` with and without -O and checks that both exit the same way and
+    // leave the same framebuffer. The code itself differs, so the program image
+    // and stack are not compared.
+    fn same_when_optimized(code: &str) -> i64 {
+        let runs: Vec<_> = [false, true]
+            .iter()
+            .map(|&optimize| {
+                let vm = run(
+                    code,
+                    &Options {
+                        optimize,
+                        ..Default::default()
+                    },
+                )
+                .expect("program runs");
+                let memory = vm.memory[FRAMEBUFFER..FRAMEBUFFER + FRAMEBUFFER_SIZE].to_vec();
+                (vm.exit_code, memory)
+            })
+            .collect();
+        assert_eq!(runs[0].0, runs[1].0, "exit codes differ");
+        assert!(runs[0].1 == runs[1].1, "memory differs");
+        runs[0].0
+    }
+
+    #[test]
+    fn loops() {
+        let code = "
+            int first_over(int limit) {
+                int n = 0;
+                while (1) {
+                    if (n * n > limit) return n;
+                    n = n + 1;
+                }
+                return -1;
+            }
+            int main(void) {
+                unsigned char *fb = (unsigned char *)0x8000;
+                int i = 0;
+                int sum = 0;
+                while (i < 10) {
+                    int j = 0;
+                    while (j < i) {
+                        fb[i * 10 + j] = i + j;
+                        sum = sum + j;
+                        j = j + 1;
+                    }
+                    i = i + 1;
+                }
+                return sum + first_over(50);
+            }";
+        assert_eq!(same_when_optimized(code), 128);
+    }
+
+    #[test]
+    fn recursion() {
+        let code = "
+            int squares[13];
+            int fib(int n) {
+                if (n < 2) return n;
+                return fib(n - 1) + fib(n - 2);
+            }
+            int depth(int *out, int n) {
+                out[n] = n * n;
+                if (n == 0) return 0;
+                return 1 + depth(out, n - 1);
+            }
+            int main(void) {
+                int *fb = (int *)0x8000;
+                int n = depth(squares, 12);
+                fb[20] = fib(15);
+                fb[n] = squares[n];
+                return n + fib(15) - 610;
+            }";
+        assert_eq!(same_when_optimized(code), 12);
+    }
+
+    #[test]
+    fn pointer_stores() {
+        let code = "
+            struct point { int x; short y; char tag; };
+            struct point points[8];
+            long wide[2];
+            long total;
+            void set(struct point *p, int x, int y) {
+                p->x = x;
+                p->y = y;
+                p->tag = x + y;
+            }
+            int main(void) {
+                unsigned char *fb = (unsigned char *)0x8000;
+                unsigned char *bytes = (unsigned char *)wide;
+                int i = 0;
+                while (i < 8) {
+                    set(&points[i], i, 0 - i);
+                    total = total + points[i].x;
+                    fb[i] = points[i].y;
+                    i = i + 1;
+                }
+                *wide = -2;
+                bytes[1] = 7;
+                *(wide + 1) = *wide;
+                return total + bytes[9];
+            }";
+        assert_eq!(same_when_optimized(code), 35);
+    }
+
+    #[test]
+    fn inline_asm() {
+        let code = "
+            int counter;
+            int twice(int x) { return x + x; }
+            void fill(unsigned char *dst, int count, int value) {
+                asm(\".loop:\\n push %count\\n loadi32\\n push .done\\n jz\\n\"
+                    \" push %value\\n loadi32\\n push %dst\\n loadi64\\n storeu8\");
+                dst = dst + 1;
+                count = count - 1;
+                asm(\"push .loop\\n jmp\\n .done:\");
+            }
+            int main(void) {
+                long r = 0;
+                fill((unsigned char *)0x8000, 16, 9);
+                asm(\"push 21\\n push twice\\n call\\n push %r\\n storei64\");
+                asm(\"push 5\\n push %counter\\n storeu32\");
+                return r + counter;
+            }";
+        assert_eq!(same_when_optimized(code), 47);
+    }
+
+    #[test]
+    fn unused_loads_still_fault() {
+        let code = "int main(void) { long *p = (long *)-8; *p; return 0; }";
+        let options = Options {
+            optimize: true,
+            ..Default::default()
+        };
+        let Err(err) = run(code, &options) else {
+            panic!("the load was optimized away");
+        };
+        assert_eq!(err.message, "Memory access out of bounds");
+    }
+}
@@ -1,182 +1,2032 @@
+use super::diagnostic::{has_errors, Diagnostic, Span};
+use super::ir::{
+    Block, BlockId, Data, Function, Op, Program, Terminator, PROTECT_INTERRUPT, RODATA_END,
+};
 use super::lexer::Token;
+use super::types::{AggregateRef, Type};
+use crate::asm::{self, Item, Operand};
+use crate::vm::MEMORY_SIZE;
 use crate::Instruction;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 #[derive(Debug)]
 pub enum ASTNode {
-    FunDecl(String, Vec<Statement>),
-    Prog(Box<crate::compiler::parser::ASTNode>),
+    // A function without a body is a forward declaration.
+    FunDecl(
+        String,
+        Type,
+        Vec<(Type, String)>,
+        Option<Vec<Statement>>,
+        Span,
+    ),
+    GlobalDecl(Declaration),
+    Prog(Vec<ASTNode>),
 }
-struct InstrValuePair {
-    instr: Instruction,
-    value: Option<i64>,
-    fn_name: Option<String>,
+#[derive(Debug)]
+pub struct Declaration {
+    pub name: String,
+    pub ty: Type,
+    pub init: Option<Initializer>,
+    pub is_static: bool,
+    // Where the name was declared.
+    pub span: Span,
+}
+#[derive(Debug)]
+pub enum Initializer {
+    Expr(Expression),
+    List(Vec<Expression>),
 }
-impl InstrValuePair {
-    fn process(&mut self, fn_offsets: &HashMap<String, usize>) {
-        if let Some(fn_name) = &self.fn_name {
-            if let Some(offset) = fn_offsets.get(fn_name) {
-                self.value = Some(*offset as i64);
+// Everything visible at file scope, plus the data segment built from it.
+#[derive(Default)]
+struct Symbols {
+    // Every function declared so far.
+    functions: HashMap<String, Signature>,
+    globals: HashMap<String, Type>,
+    data: Vec<Data>,
+    strings: Vec<Vec<u8>>,
+    // Every symbol whose address was taken, and where.
+    references: Vec<(String, Option<Span>)>,
+    diagnostics: Vec<Diagnostic>,
+}
+impl Symbols {
+    fn error(&mut self, span: Option<Span>, message: String) {
+        self.diagnostics.push(Diagnostic::error(span, message));
+    }
+    // Names in `scopes` hide the globals while encoding the initializer.
+    fn define_data(&mut self, symbol: String, decl: &Declaration, scopes: &[Scope]) {
+        let ty = decl.ty.clone();
+        if self.data.iter().any(|item| item.symbol == symbol) {
+            self.error(Some(decl.span), format!("redefinition of {}", decl.name));
+            return;
+        }
+        let mut relocations = Vec::new();
+        let init = match decl
+            .init
+            .as_ref()
+            .map(|init| self.encode(init, &ty, scopes, &mut relocations))
+        {
+            Some(Err((span, message))) => {
+                self.error(Some(span), message);
+                None
+            }
+            Some(Ok(bytes)) => Some(bytes),
+            None => None,
+        };
+        self.data.push(Data {
+            symbol,
+            size: ty.size(),
+            align: ty.align(),
+            init,
+            relocations,
+        });
+    }
+    // Encodes a constant initializer the way the value is laid out in memory.
+    fn encode(
+        &mut self,
+        init: &Initializer,
+        ty: &Type,
+        scopes: &[Scope],
+        relocations: &mut Vec<(usize, String)>,
+    ) -> Result<Vec<u8>, (Span, String)> {
+        let mut bytes = vec![0; ty.size()];
+        match (init, ty.elements()) {
+            (Initializer::List(exps), Some(elements)) => {
+                if let Some(exp) = exps.get(elements.len()) {
+                    return Err((exp.span, format!("too many initializers for {}", ty)));
+                }
+                for (exp, (offset, elem)) in exps.iter().zip(elements) {
+                    if !elem.is_scalar() {
+                        let message = format!("nested initializer needed for {}", elem);
+                        return Err((exp.span, message));
+                    }
+                    let bytes = &mut bytes[offset..];
+                    self.encode_scalar(exp, &elem, bytes, offset, scopes, relocations)
+                        .map_err(|message| (exp.span, message))?;
+                }
+            }
+            (Initializer::Expr(exp), _) if ty.is_scalar() => self
+                .encode_scalar(exp, ty, &mut bytes, 0, scopes, relocations)
+                .map_err(|message| (exp.span, message))?,
+            (Initializer::Expr(exp), _) => {
+                return Err((exp.span, format!("invalid initializer for {}", ty)))
+            }
+            (Initializer::List(exps), _) => {
+                let span = exps.first().map_or(Span::default(), |exp| exp.span);
+                return Err((span, format!("invalid initializer for {}", ty)));
             }
         }
+        Ok(bytes)
     }
-    fn to_bytes(&mut self) -> Vec<u8> {
-        let mut vec = Vec::new();
-        vec.push(self.instr as u8);
-        if let Some(value) = self.value {
-            vec.append(&mut value.to_le_bytes().to_vec());
+    fn encode_scalar(
+        &mut self,
+        exp: &Expression,
+        ty: &Type,
+        bytes: &mut [u8],
+        offset: usize,
+        scopes: &[Scope],
+        relocations: &mut Vec<(usize, String)>,
+    ) -> Result<(), String> {
+        if let ExprKind::Str(string) = &exp.kind {
+            if !ty.is_pointer() {
+                return Err(format!("initializing {} from a string literal", ty));
+            }
+            relocations.push((offset, self.string(string)));
+            return Ok(());
         }
-        if let Some(_fn_name) = &self.fn_name {
-            vec.append(&mut vec![0; 8]);
+        // Variables with a fixed address, and arrays that decay to theirs, are
+        // constants the linker fills in.
+        let address = match &exp.kind {
+            ExprKind::AddrOf(inner) => match &inner.kind {
+                ExprKind::Var(name) => self
+                    .static_variable(name, scopes)
+                    .map(|(symbol, var_ty)| (symbol, Type::Pointer(Box::new(var_ty)))),
+                _ => None,
+            },
+            ExprKind::Var(name) => self
+                .static_variable(name, scopes)
+                .filter(|(_, var_ty)| matches!(var_ty, Type::Array(..)))
+                .map(|(symbol, var_ty)| (symbol, var_ty.decay())),
+            _ => None,
+        };
+        if let Some((symbol, from)) = address {
+            let ok =
+                ty.is_pointer() && (&from == ty || from.is_void_pointer() || ty.is_void_pointer());
+            if !ok {
+                return Err(format!("initializing {} from {}", ty, from));
+            }
+            relocations.push((offset, symbol));
+            return Ok(());
+        }
+        let value = exp.eval_const()?;
+        if ty.is_pointer() && value != 0 && !matches!(exp.kind, ExprKind::Cast(..)) {
+            return Err(format!("initializing {} from an integer needs a cast", ty));
         }
-        vec
+        self.diagnostics.extend(exp.truncation_warning(ty));
+        bytes[..ty.size()].copy_from_slice(&value.to_le_bytes()[..ty.size()]);
+        Ok(())
+    }
+    // The symbol and type of `name` if it is a global or static local.
+    fn static_variable(&self, name: &str, scopes: &[Scope]) -> Option<(String, Type)> {
+        match scopes.iter().rev().find_map(|scope| scope.get(name)) {
+            Some(Variable {
+                ty,
+                location: Location::Static(symbol),
+            }) => Some((symbol.clone(), ty.clone())),
+            Some(_) => None,
+            None => self
+                .globals
+                .get(name)
+                .map(|ty| (name.to_string(), ty.clone())),
+        }
+    }
+    // The symbol of a string literal; identical literals share their storage.
+    fn string(&mut self, string: &[u8]) -> String {
+        let index = match self.strings.iter().position(|s| s == string) {
+            Some(index) => index,
+            None => {
+                self.strings.push(string.to_vec());
+                self.strings.len() - 1
+            }
+        };
+        format!(".str{}", index)
     }
 }
-impl ASTNode {
-    pub fn emit(&self, instrs: &mut Vec<u8>) {
-        let mut functions = HashMap::new();
-        match self {
-            ASTNode::FunDecl(name, stmts) => {
-                let mut pair = Vec::<InstrValuePair>::new();
-                for stmt in stmts {
-                    stmt.visit(&mut pair);
+// An interrupt C code can raise with `__interrupt(number, args...)`. The arguments
+// are pushed left to right, and none of them leave a result.
+struct Interrupt {
+    number: u8,
+    name: &'static str,
+    args: usize,
+}
+const INTERRUPTS: &[Interrupt] = &[
+    Interrupt {
+        number: 0x00,
+        name: "write pixel by index",
+        args: 2,
+    },
+    Interrupt {
+        number: 0x01,
+        name: "write pixel at x, y",
+        args: 3,
+    },
+    Interrupt {
+        number: 0x02,
+        name: "end of frame",
+        args: 0,
+    },
+    Interrupt {
+        number: 0x03,
+        name: "set display mode",
+        args: 3,
+    },
+    Interrupt {
+        number: 0x04,
+        name: "set palette entry",
+        args: 2,
+    },
+    Interrupt {
+        number: 0x05,
+        name: "clear",
+        args: 1,
+    },
+    Interrupt {
+        number: 0x06,
+        name: "fill rectangle",
+        args: 5,
+    },
+    Interrupt {
+        number: 0x07,
+        name: "draw line",
+        args: 5,
+    },
+    Interrupt {
+        number: 0x08,
+        name: "blit",
+        args: 6,
+    },
+    Interrupt {
+        number: 0x09,
+        name: "draw text",
+        args: 4,
+    },
+    Interrupt {
+        number: 0x0a,
+        name: "next event",
+        args: 0,
+    },
+    Interrupt {
+        number: 0x0b,
+        name: "present",
+        args: 0,
+    },
+    Interrupt {
+        number: 0x0c,
+        name: "set timer",
+        args: 2,
+    },
+];
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Signature {
+    ret: Type,
+    params: Vec<Type>,
+}
+impl Signature {
+    // Formats the signature as a declaration of `name`.
+    fn display<'a>(&'a self, name: &'a str) -> impl fmt::Display + 'a {
+        struct Declarator<'a>(&'a Signature, &'a str);
+        impl fmt::Display for Declarator<'_> {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{} {}(", self.0.ret, self.1)?;
+                for (i, param) in self.0.params.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", param)?;
                 }
-                functions.insert(name.clone(), pair);
+                write!(f, ")")
+            }
+        }
+        Declarator(self, name)
+    }
+}
+#[derive(Debug, Clone)]
+enum Location {
+    Frame(i64),
+    Static(String),
+}
+#[derive(Debug, Clone)]
+struct Variable {
+    ty: Type,
+    location: Location,
+}
+// The variables declared in a block.
+type Scope = HashMap<String, Variable>;
+struct FunctionBuilder<'a> {
+    name: String,
+    ret: Type,
+    blocks: Vec<Block>,
+    // The blocks in the order they were placed, which is how they are laid out.
+    order: Vec<BlockId>,
+    current: BlockId,
+    // Whether the current block already ends in a jump or return.
+    finished: bool,
+    scopes: Vec<Scope>,
+    next_offset: i64,
+    frame_size: i64,
+    // Static locals declared so far, which numbers their symbols.
+    statics: usize,
+    symbols: &'a mut Symbols,
+    // What errors point at: the expression being visited, or else the statement.
+    span: Span,
+    // Labels defined by inline assembly, and where each one is used.
+    asm_labels: HashSet<String>,
+    asm_references: Vec<(String, Span)>,
+}
+impl<'a> FunctionBuilder<'a> {
+    fn new(name: &str, ret: &Type, span: Span, symbols: &'a mut Symbols) -> FunctionBuilder<'a> {
+        let mut builder = FunctionBuilder {
+            name: name.to_string(),
+            ret: ret.clone(),
+            blocks: Vec::new(),
+            order: Vec::new(),
+            current: 0,
+            finished: false,
+            scopes: vec![HashMap::new()],
+            next_offset: 0,
+            frame_size: 0,
+            statics: 0,
+            symbols,
+            span,
+            asm_labels: HashSet::new(),
+            asm_references: Vec::new(),
+        };
+        let entry = builder.new_block();
+        builder.place(entry);
+        builder
+    }
+    fn error(&mut self, message: String) {
+        let span = self.span;
+        self.symbols.error(Some(span), message);
+    }
+    // Code after a jump or return is unreachable, but still goes somewhere so it gets checked.
+    fn open(&mut self) {
+        if self.finished {
+            let block = self.new_block();
+            self.place(block);
+        }
+    }
+    fn push(&mut self, op: Op) {
+        self.open();
+        self.blocks[self.current].ops.push(op);
+    }
+    // Pushes the address of a function or of static data.
+    fn push_symbol(&mut self, symbol: &str) {
+        self.symbols
+            .references
+            .push((symbol.to_string(), Some(self.span)));
+        self.push(Op::Address(symbol.to_string()));
+    }
+    fn new_block(&mut self) -> BlockId {
+        // Every block is finished before the function is, so the terminator is a placeholder.
+        self.blocks.push(Block {
+            ops: Vec::new(),
+            term: Terminator::Return,
+        });
+        self.blocks.len() - 1
+    }
+    // Ends the current block. A terminator in unreachable code is dropped.
+    fn finish(&mut self, term: Terminator) {
+        if !self.finished {
+            self.blocks[self.current].term = term;
+            self.finished = true;
+        }
+    }
+    // Continues in `block`, falling through to it if the current block is not finished.
+    fn place(&mut self, block: BlockId) {
+        self.finish(Terminator::Jump(block));
+        self.order.push(block);
+        self.current = block;
+        self.finished = false;
+    }
+    // Where the next op goes, for `insert` and `truncate`.
+    fn position(&mut self) -> (BlockId, usize) {
+        self.open();
+        (self.current, self.blocks[self.current].ops.len())
+    }
+    fn insert(&mut self, (block, index): (BlockId, usize), ops: Vec<Op>) {
+        self.blocks[block].ops.splice(index..index, ops);
+    }
+    fn truncate(&mut self, (block, index): (BlockId, usize)) {
+        self.blocks[block].ops.truncate(index);
+    }
+    fn declare(&mut self, name: &str, ty: &Type) -> Variable {
+        // Every local gets its own 8-byte aligned slot in the frame.
+        let variable = Variable {
+            ty: ty.clone(),
+            location: Location::Frame(self.next_offset),
+        };
+        self.next_offset += (ty.size().div_ceil(8) * 8) as i64;
+        self.frame_size = self.frame_size.max(self.next_offset);
+        self.add_to_scope(name, variable.clone());
+        variable
+    }
+    fn declare_static(&mut self, decl: &Declaration) {
+        // Blocks can each have a static of the same name.
+        let symbol = format!("{}.{}.{}", self.name, decl.name, self.statics);
+        self.statics += 1;
+        self.symbols.define_data(symbol.clone(), decl, &self.scopes);
+        let variable = Variable {
+            ty: decl.ty.clone(),
+            location: Location::Static(symbol),
+        };
+        self.add_to_scope(&decl.name, variable);
+    }
+    fn add_to_scope(&mut self, name: &str, variable: Variable) {
+        if variable.ty.size() == 0 {
+            self.error(format!(
+                "variable {} has incomplete type {}",
+                name, variable.ty
+            ));
+        }
+        let scope = self.scopes.last_mut().unwrap();
+        if scope.insert(name.to_string(), variable).is_some() {
+            self.error(format!("redeclaration of {}", name));
+        }
+    }
+    fn lookup(&mut self, name: &str) -> Option<Variable> {
+        if let Some(variable) = self.scopes.iter().rev().find_map(|scope| scope.get(name)) {
+            return Some(variable.clone());
+        }
+        match self.symbols.globals.get(name) {
+            Some(ty) => Some(Variable {
+                ty: ty.clone(),
+                location: Location::Static(name.to_string()),
+            }),
+            None => {
+                self.error(format!("undeclared variable {}", name));
+                None
+            }
+        }
+    }
+    fn push_address(&mut self, variable: &Variable) {
+        match &variable.location {
+            Location::Frame(offset) => {
+                self.push(Op::FramePointer);
+                self.push(Op::Push(*offset));
+                self.push(Op::Add);
             }
-            &ASTNode::Prog(ref node) => {
-                node.emit(instrs);
+            Location::Static(symbol) => self.push_symbol(symbol),
+        }
+    }
+    fn store(&mut self, ty: &Type) {
+        match ty.store() {
+            Some(store) => self.push(store),
+            None => self.error(format!("cannot assign to {}", ty)),
+        }
+    }
+    // The op converting a scalar value from one type to another, if one is needed.
+    fn conversion(from: &Type, to: &Type) -> Option<Op> {
+        if !to.is_integer() || to.size() == 8 || (from.is_integer() && to.contains(from)) {
+            return None;
+        }
+        let size = to.size() as u8;
+        Some(if to.is_signed() {
+            Op::SignExtend(size)
+        } else {
+            Op::ZeroExtend(size)
+        })
+    }
+    // Converts the value on top of the stack.
+    fn convert(&mut self, from: &Type, to: &Type) {
+        if let Some(conversion) = FunctionBuilder::conversion(from, to) {
+            self.push(conversion);
+        }
+    }
+    // Same as `convert`, for the value just below the top of the stack.
+    fn convert_second(&mut self, from: &Type, to: &Type) {
+        if let Some(conversion) = FunctionBuilder::conversion(from, to) {
+            self.push(Op::Swap);
+            self.push(conversion);
+            self.push(Op::Swap);
+        }
+    }
+    // Keeps the result of 32-bit unsigned arithmetic in range; signed overflow is undefined.
+    fn wrap(&mut self, ty: &Type) {
+        if *ty == Type::UInt {
+            self.push(Op::ZeroExtend(4));
+        }
+    }
+    // Reports an error unless the value of `exp`, of type `from`, may be assigned to `to`.
+    fn check_assign(&mut self, from: &Type, to: &Type, exp: &Expression, context: &str) {
+        self.symbols.diagnostics.extend(exp.truncation_warning(to));
+        let is_null = exp.is_null();
+        let ok = match (from, to) {
+            (from, to) if from.is_integer() && to.is_integer() => true,
+            (Type::Pointer(from_elem), Type::Pointer(to_elem)) => {
+                from_elem == to_elem || from.is_void_pointer() || to.is_void_pointer()
             }
+            (from, Type::Pointer(_)) => from.is_integer() && is_null,
+            _ => false,
+        };
+        if !ok {
+            self.error(format!(
+                "incompatible types in {}: {} to {}",
+                context, from, to
+            ));
+        }
+    }
+    fn function(mut self, params: &[(Type, String)], stmts: &[Statement]) -> Function {
+        self.push(Op::Location(self.span));
+        let variables: Vec<Variable> = params
+            .iter()
+            .map(|(ty, param)| self.declare(param, ty))
+            .collect();
+        // Arguments were pushed left to right, so the last one is on top.
+        for variable in variables.iter().rev() {
+            self.push_address(variable);
+            self.store(&variable.ty);
         }
-        let mut fn_offsets = HashMap::new();
-        let mut offset: usize = 0;
-        for (name, pairs) in functions {
-            for mut pair in pairs {
-                pair.process(&fn_offsets);
-                instrs.append(&mut pair.to_bytes());
+        for stmt in stmts {
+            stmt.visit(&mut self);
+        }
+        // Falling off the end returns 0, so every call leaves exactly one value.
+        self.push(Op::Push(0));
+        self.finish(Terminator::Return);
+        for (label, span) in std::mem::take(&mut self.asm_references) {
+            if !self.asm_labels.contains(&label) {
+                self.symbols
+                    .error(Some(span), format!("undefined label {}", label));
             }
-            fn_offsets.insert(name, offset);
-            offset += instrs.len();
+        }
+        // Renumber the blocks in layout order.
+        let mut index = vec![0; self.blocks.len()];
+        for (i, id) in self.order.iter().enumerate() {
+            index[*id] = i;
+        }
+        let mut blocks: Vec<(usize, Block)> = self
+            .blocks
+            .into_iter()
+            .enumerate()
+            .map(|(id, mut block)| {
+                block.term.retarget(|target| index[target]);
+                (index[id], block)
+            })
+            .collect();
+        blocks.sort_by_key(|(index, _)| *index);
+        Function {
+            name: self.name,
+            frame_size: self.frame_size,
+            blocks: blocks.into_iter().map(|(_, block)| block).collect(),
+        }
+    }
+}
+impl ASTNode {
+    // Problems are added to `diagnostics`; the IR is only meaningful if there were none.
+    // Symbols in `external` are defined by the runtime library, which is linked in later.
+    pub fn lower(&self, external: &HashSet<String>, diagnostics: &mut Vec<Diagnostic>) -> Program {
+        // Execution starts here no matter where main is defined. What main returns
+        // becomes the exit status; a void main leaves 0 behind. First the code and
+        // string literals are made read-only.
+        let entry = Function {
+            name: String::new(),
+            frame_size: 0,
+            blocks: vec![Block {
+                ops: vec![
+                    Op::Address(RODATA_END.to_string()),
+                    Op::Interrupt(PROTECT_INTERRUPT),
+                    Op::Address("main".to_string()),
+                    Op::Call,
+                ],
+                term: Terminator::Exit,
+            }],
+        };
+        let mut symbols = Symbols::default();
+        symbols.references.push(("main".to_string(), None));
+        self.lower_decls(vec![entry], symbols, external, diagnostics)
+    }
+    // A library has no entry point, and only needs to define what it uses itself.
+    pub fn lower_library(&self, diagnostics: &mut Vec<Diagnostic>) -> Program {
+        self.lower_decls(Vec::new(), Symbols::default(), &HashSet::new(), diagnostics)
+    }
+    fn lower_decls(
+        &self,
+        mut functions: Vec<Function>,
+        mut symbols: Symbols,
+        external: &HashSet<String>,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> Program {
+        let decls = match self {
+            ASTNode::Prog(nodes) => nodes.iter().collect::<Vec<_>>(),
+            node => vec![node],
+        };
+        for decl in decls {
+            match decl {
+                ASTNode::FunDecl(name, ret, params, body, span) => {
+                    // Nothing passes arguments to main.
+                    if name == "main" && !params.is_empty() {
+                        symbols.error(Some(*span), "main cannot take parameters".to_string());
+                    }
+                    let signature = Signature {
+                        ret: ret.clone(),
+                        params: params.iter().map(|(ty, _)| ty.clone()).collect(),
+                    };
+                    match symbols.functions.insert(name.clone(), signature.clone()) {
+                        Some(previous) if previous != signature => {
+                            let message = format!("conflicting declarations of {}", name);
+                            symbols.diagnostics.push(
+                                Diagnostic::error(Some(*span), message).with_note(format!(
+                                    "previously declared as {}",
+                                    previous.display(name)
+                                )),
+                            );
+                        }
+                        _ => {}
+                    }
+                    if let Some(stmts) = body {
+                        if functions.iter().any(|function| &function.name == name) {
+                            symbols.error(Some(*span), format!("redefinition of {}", name));
+                        }
+                        let builder = FunctionBuilder::new(name, ret, *span, &mut symbols);
+                        functions.push(builder.function(params, stmts));
+                    }
+                }
+                ASTNode::GlobalDecl(decl) => {
+                    if decl.ty.size() == 0 {
+                        symbols.error(
+                            Some(decl.span),
+                            format!("variable {} has incomplete type {}", decl.name, decl.ty),
+                        );
+                    }
+                    symbols.globals.insert(decl.name.clone(), decl.ty.clone());
+                    symbols.define_data(decl.name.clone(), decl, &[]);
+                }
+                ASTNode::Prog(_) => symbols.error(None, "nested program".to_string()),
+            }
+        }
+        // Undefined references are usually caused by an error that was already reported.
+        if !has_errors(&symbols.diagnostics) {
+            let mut defined: HashSet<String> = functions
+                .iter()
+                .map(|function| function.name.clone())
+                .chain(symbols.data.iter().map(|item| item.symbol.clone()))
+                .chain((0..symbols.strings.len()).map(|index| format!(".str{}", index)))
+                .chain(external.iter().cloned())
+                .collect();
+            for (symbol, span) in &symbols.references {
+                // One report per undefined symbol is enough.
+                if defined.insert(symbol.clone()) {
+                    let message = format!("undefined reference to {}", symbol);
+                    symbols.diagnostics.push(Diagnostic::error(*span, message));
+                }
+            }
+        }
+        diagnostics.append(&mut symbols.diagnostics);
+        Program {
+            functions,
+            strings: symbols.strings,
+            data: symbols.data,
         }
     }
 }
 #[derive(Debug)]
 pub enum Statement {
     Exp(Expression),
-    Return(Box<Expression>),
+    Return(Option<Expression>, Span),
+    Declare(Declaration),
+    If(Expression, Box<Statement>, Option<Box<Statement>>),
+    While(Expression, Box<Statement>),
+    Block(Vec<Statement>),
+    // The text of an `asm("...")` statement.
+    Asm(String, Span),
+    Empty,
 }
 impl Statement {
-    fn visit(&self, pairs: &mut Vec<InstrValuePair>) {
+    fn visit(&self, builder: &mut FunctionBuilder) {
         match self {
             Statement::Exp(exp) => {
-                exp.visit(pairs);
-            }
-            Statement::Return(exp) => {
-                exp.visit(pairs);
-                pairs.push(InstrValuePair {
-                    instr: Instruction::Ret,
-                    value: None,
-                    fn_name: None,
+                builder.push(Op::Location(exp.span));
+                exp.visit(builder);
+                builder.push(Op::Pop);
+            }
+            Statement::Return(None, span) => {
+                builder.span = *span;
+                builder.push(Op::Location(*span));
+                if builder.ret != Type::Void {
+                    builder.error("return without a value".to_string());
+                }
+                builder.push(Op::Push(0));
+                builder.finish(Terminator::Return);
+            }
+            Statement::Return(Some(exp), span) => {
+                builder.span = *span;
+                builder.push(Op::Location(*span));
+                let ty = exp.visit(builder);
+                if builder.ret == Type::Void {
+                    builder.error("return with a value in a void function".to_string());
+                } else {
+                    let ret = builder.ret.clone();
+                    builder.check_assign(&ty, &ret, exp, "return");
+                    builder.convert(&ty, &ret);
+                }
+                builder.finish(Terminator::Return);
+            }
+            Statement::Declare(decl) if decl.is_static => {
+                builder.span = decl.span;
+                builder.declare_static(decl);
+            }
+            Statement::Declare(decl) => {
+                builder.span = decl.span;
+                if decl.init.is_some() {
+                    builder.push(Op::Location(decl.span));
+                }
+                let variable = builder.declare(&decl.name, &decl.ty);
+                match (&decl.init, decl.ty.elements()) {
+                    (None, _) => {}
+                    (Some(Initializer::List(exps)), Some(elements)) => {
+                        if exps.len() > elements.len() {
+                            builder.error(format!("too many initializers for {}", decl.name));
+                        }
+                        // Frames are reused, so the elements without an initializer are zeroed.
+                        for (i, (offset, elem)) in elements.iter().enumerate() {
+                            match exps.get(i) {
+                                Some(exp) => {
+                                    let ty = exp.visit(builder);
+                                    builder.check_assign(&ty, elem, exp, "initializer");
+                                    builder.convert(&ty, elem);
+                                }
+                                None => builder.push(Op::Push(0)),
+                            }
+                            builder.push_address(&variable);
+                            builder.push(Op::Push(*offset as i64));
+                            builder.push(Op::Add);
+                            builder.store(elem);
+                        }
+                    }
+                    (Some(Initializer::Expr(exp)), _) if decl.ty.is_scalar() => {
+                        let from = exp.visit(builder);
+                        builder.check_assign(&from, &decl.ty, exp, "initializer");
+                        builder.convert(&from, &decl.ty);
+                        builder.push_address(&variable);
+                        builder.store(&decl.ty);
+                    }
+                    _ => builder.error(format!("invalid initializer for {}", decl.name)),
+                }
+            }
+            Statement::If(cond, then, otherwise) => {
+                let then_block = builder.new_block();
+                let end_block = builder.new_block();
+                builder.push(Op::Location(cond.span));
+                cond.visit_condition(builder);
+                match otherwise {
+                    Some(otherwise) => {
+                        let else_block = builder.new_block();
+                        builder.finish(Terminator::Branch {
+                            nonzero: then_block,
+                            zero: else_block,
+                        });
+                        builder.place(then_block);
+                        then.visit(builder);
+                        builder.finish(Terminator::Jump(end_block));
+                        builder.place(else_block);
+                        otherwise.visit(builder);
+                    }
+                    None => {
+                        builder.finish(Terminator::Branch {
+                            nonzero: then_block,
+                            zero: end_block,
+                        });
+                        builder.place(then_block);
+                        then.visit(builder);
+                    }
+                }
+                builder.place(end_block);
+            }
+            Statement::While(cond, body) => {
+                let top_block = builder.new_block();
+                let body_block = builder.new_block();
+                let end_block = builder.new_block();
+                builder.place(top_block);
+                builder.push(Op::Location(cond.span));
+                cond.visit_condition(builder);
+                builder.finish(Terminator::Branch {
+                    nonzero: body_block,
+                    zero: end_block,
                 });
+                builder.place(body_block);
+                body.visit(builder);
+                builder.finish(Terminator::Jump(top_block));
+                builder.place(end_block);
+            }
+            Statement::Block(stmts) => {
+                let saved_offset = builder.next_offset;
+                builder.scopes.push(HashMap::new());
+                for stmt in stmts {
+                    stmt.visit(builder);
+                }
+                builder.scopes.pop();
+                builder.next_offset = saved_offset;
+            }
+            Statement::Asm(text, span) => {
+                builder.span = *span;
+                builder.push(Op::Location(*span));
+                builder.inline_asm(text);
             }
+            Statement::Empty => {}
         }
     }
 }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+impl BinaryOp {
+    fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Equal => "==",
+            BinaryOp::NotEqual => "!=",
+            BinaryOp::Less => "<",
+            BinaryOp::LessEqual => "<=",
+            BinaryOp::Greater => ">",
+            BinaryOp::GreaterEqual => ">=",
+        }
+    }
+    fn is_comparison(self) -> bool {
+        !matches!(
+            self,
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div
+        )
+    }
+}
+#[derive(Debug)]
+pub struct Expression {
+    pub kind: ExprKind,
+    pub span: Span,
+}
 #[derive(Debug)]
-pub enum Expression {
+pub enum ExprKind {
     Num(i64),
-    // Add(Box<Expression>, Box<Expression>),
-    // Sub(Box<Expression>, Box<Expression>),
-    // Mul(Box<Expression>, Box<Expression>),
-    // Div(Box<Expression>, Box<Expression>),
+    Str(Vec<u8>),
+    Var(String),
+    Assign(Box<Expression>, Box<Expression>),
+    Neg(Box<Expression>),
+    Not(Box<Expression>),
+    Deref(Box<Expression>),
+    AddrOf(Box<Expression>),
+    Index(Box<Expression>, Box<Expression>),
+    Cast(Type, Box<Expression>),
+    Member(Box<Expression>, String),
+    Arrow(Box<Expression>, String),
+    SizeofExpr(Box<Expression>),
+    SizeofType(Type),
+    Binary(BinaryOp, Box<Expression>, Box<Expression>),
+    Call(String, Vec<Expression>),
 }
 impl Expression {
-    fn visit(&self, pairs: &mut Vec<InstrValuePair>) {
-        match self {
-            Expression::Num(num) => {
-                pairs.push(InstrValuePair {
-                    instr: Instruction::Push,
-                    value: Some(*num),
-                    fn_name: None,
-                });
-            } // Expression::Add(lhs, rhs) => {
-              //     lhs.visit(pairs);
-              //     rhs.visit(pairs);
-              //     pairs.push(InstrValuePair {
-              //         instr: Instruction::Add,
-              //         value: None,
-              //     });
-              // }
-              // Expression::Sub(lhs, rhs) => {
-              //     lhs.visit(pairs);
-              //     rhs.visit(pairs);
-              //     pairs.push(InstrValuePair {
-              //         instr: Instruction::Sub,
-              //         value: None,
-              //     });
-              // }
-              // Expression::Mul(lhs, rhs) => {
-              //     lhs.visit(pairs);
-              //     rhs.visit(pairs);
-              //     pairs.push(InstrValuePair {
-              //         instr: Instruction::Mul,
-              //         value: None,
-              //     });
-              // }
-              // Expression::Div(lhs, rhs) => {
-              //     lhs.visit(pairs);
-              //     rhs.visit(pairs);
-              //     pairs.push(InstrValuePair {
-              //         instr: Instruction::Div,
-              //         value: None,
-              //     });
-              // }
-        }
-    }
-}
-pub fn parse(tokens: Vec<Token>) -> ASTNode {
-    let mut tokens = tokens;
-    let mut ast = ASTNode::Prog(Box::new(ASTNode::FunDecl("main".to_string(), vec![])));
-    while tokens.len() > 0 {
-        match tokens.remove(0) {
-            Token::Int => {
-                let name = match tokens.remove(0) {
-                    Token::Identifier(name) => name,
-                    _ => panic!("Expected identifier"),
+    fn new(kind: ExprKind, span: Span) -> Expression {
+        Expression { kind, span }
+    }
+    fn is_null(&self) -> bool {
+        matches!(self.kind, ExprKind::Num(0))
+    }
+    // Warns when a constant changes value on its way into `to`.
+    fn truncation_warning(&self, to: &Type) -> Option<Diagnostic> {
+        let value = self.eval_const().ok()?;
+        if to.fits(value) {
+            return None;
+        }
+        let message = format!(
+            "conversion to {} changes value from {} to {}",
+            to,
+            value,
+            to.wrap(value)
+        );
+        Some(Diagnostic::warning(Some(self.span), message))
+    }
+    fn eval_const(&self) -> Result<i64, String> {
+        Ok(match &self.kind {
+            ExprKind::Num(num) => *num,
+            ExprKind::Neg(exp) => exp.eval_const()?.wrapping_neg(),
+            ExprKind::Not(exp) => (exp.eval_const()? == 0) as i64,
+            ExprKind::Cast(ty, exp) => ty.wrap(exp.eval_const()?),
+            ExprKind::SizeofType(ty) => ty.size() as i64,
+            ExprKind::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval_const()?;
+                let rhs = rhs.eval_const()?;
+                match op {
+                    BinaryOp::Add => lhs.wrapping_add(rhs),
+                    BinaryOp::Sub => lhs.wrapping_sub(rhs),
+                    BinaryOp::Mul => lhs.wrapping_mul(rhs),
+                    BinaryOp::Div if rhs == 0 => {
+                        return Err("division by zero in constant".to_string())
+                    }
+                    BinaryOp::Div => lhs.wrapping_div(rhs),
+                    BinaryOp::Equal => (lhs == rhs) as i64,
+                    BinaryOp::NotEqual => (lhs != rhs) as i64,
+                    BinaryOp::Less => (lhs < rhs) as i64,
+                    BinaryOp::LessEqual => (lhs <= rhs) as i64,
+                    BinaryOp::Greater => (lhs > rhs) as i64,
+                    BinaryOp::GreaterEqual => (lhs >= rhs) as i64,
+                }
+            }
+            _ => return Err("initializer is not a constant".to_string()),
+        })
+    }
+    // Evaluates a controlling expression, which must be scalar.
+    fn visit_condition(&self, builder: &mut FunctionBuilder) {
+        let ty = self.visit(builder);
+        if !ty.is_scalar() {
+            builder.error(format!("used {} where a scalar is required", ty));
+        }
+    }
+    // Pushes the address of an lvalue and returns the type of the object there.
+    fn visit_address(&self, builder: &mut FunctionBuilder) -> Type {
+        let outer = std::mem::replace(&mut builder.span, self.span);
+        let ty = self.visit_address_inner(builder);
+        builder.span = outer;
+        ty
+    }
+    fn visit_address_inner(&self, builder: &mut FunctionBuilder) -> Type {
+        match &self.kind {
+            ExprKind::Var(name) => match builder.lookup(name) {
+                Some(variable) => {
+                    builder.push_address(&variable);
+                    variable.ty
+                }
+                None => {
+                    builder.push(Op::Push(0));
+                    Type::Int
+                }
+            },
+            ExprKind::Deref(exp) => match exp.visit(builder) {
+                Type::Pointer(ty) if *ty == Type::Void => {
+                    builder.error("dereferencing a void pointer".to_string());
+                    Type::Int
+                }
+                Type::Pointer(ty) => *ty,
+                ty => {
+                    builder.error(format!("cannot dereference {}", ty));
+                    Type::Int
+                }
+            },
+            ExprKind::Index(array, index) => {
+                let ty = array.visit(builder);
+                let index_ty = index.visit(builder);
+                match builder.add(&ty, &index_ty) {
+                    Type::Pointer(ty) => *ty,
+                    _ => {
+                        builder.error(format!("cannot index {}", ty));
+                        Type::Int
+                    }
+                }
+            }
+            ExprKind::Member(exp, name) => {
+                let ty = exp.visit_address(builder);
+                builder.member(&ty, name)
+            }
+            ExprKind::Str(string) => {
+                let symbol = builder.symbols.string(string);
+                builder.push_symbol(&symbol);
+                Type::Array(Box::new(Type::Char), string.len() + 1)
+            }
+            ExprKind::Arrow(exp, name) => match exp.visit(builder) {
+                Type::Pointer(ty) => builder.member(&ty, name),
+                ty => {
+                    builder.error(format!("-> used on {}", ty));
+                    Type::Int
+                }
+            },
+            _ => {
+                builder.error("expression is not assignable".to_string());
+                self.visit(builder);
+                Type::Int
+            }
+        }
+    }
+    // Pushes the value of the expression and returns its type.
+    fn visit(&self, builder: &mut FunctionBuilder) -> Type {
+        // Errors found while visiting point at the innermost expression.
+        let outer = std::mem::replace(&mut builder.span, self.span);
+        let ty = self.visit_inner(builder);
+        builder.span = outer;
+        ty
+    }
+    fn visit_inner(&self, builder: &mut FunctionBuilder) -> Type {
+        match &self.kind {
+            ExprKind::Num(num) => {
+                builder.push(Op::Push(*num));
+                if Type::Int.wrap(*num) == *num {
+                    Type::Int
+                } else {
+                    Type::Long
+                }
+            }
+            ExprKind::Var(_)
+            | ExprKind::Deref(_)
+            | ExprKind::Index(..)
+            | ExprKind::Member(..)
+            | ExprKind::Arrow(..)
+            | ExprKind::Str(_) => {
+                let ty = self.visit_address(builder);
+                match ty.load() {
+                    Some(load) => {
+                        builder.push(load);
+                        ty
+                    }
+                    // Arrays decay to a pointer to their first element.
+                    None => ty.decay(),
+                }
+            }
+            ExprKind::AddrOf(exp) => Type::Pointer(Box::new(exp.visit_address(builder))),
+            ExprKind::SizeofType(ty) => builder.push_size(ty),
+            ExprKind::SizeofExpr(exp) => {
+                // The operand is only looked at for its type, never evaluated, so
+                // the code, references and string literals it needed are dropped.
+                let start = builder.position();
+                let references = builder.symbols.references.len();
+                let strings = builder.symbols.strings.len();
+                let ty = match exp.kind {
+                    ExprKind::Str(ref string) => {
+                        Type::Array(Box::new(Type::Char), string.len() + 1)
+                    }
+                    ExprKind::Var(_)
+                    | ExprKind::Deref(_)
+                    | ExprKind::Index(..)
+                    | ExprKind::Member(..)
+                    | ExprKind::Arrow(..) => exp.visit_address(builder),
+                    _ => exp.visit(builder),
                 };
-                assert_eq!(tokens.remove(0), Token::OpenParen);
-                assert_eq!(tokens.remove(0), Token::CloseParen);
-
-                let mut statements = vec![];
-                assert_eq!(tokens.remove(0), Token::OpenBrace);
-                while tokens.is_empty() {
-                    match tokens.remove(0) {
-                        Token::Return => match tokens.remove(0) {
-                            Token::Integer(num) => {
-                                statements.push(Statement::Return(Box::new(Expression::Num(num))));
-                                assert_eq!(tokens.remove(0), Token::Semicolon);
-                                break;
-                            }
-                            _ => {
-                                panic!("Unexpected token");
+                builder.truncate(start);
+                builder.symbols.references.truncate(references);
+                builder.symbols.strings.truncate(strings);
+                builder.push_size(&ty)
+            }
+            ExprKind::Assign(lhs, rhs) => {
+                let from = rhs.visit(builder);
+                let at = builder.position();
+                let to = lhs.visit_address(builder);
+                builder.check_assign(&from, &to, rhs, "assignment");
+                // The conversion depends on the target's type, so it goes in before the address.
+                let mut ops: Vec<_> = FunctionBuilder::conversion(&from, &to)
+                    .into_iter()
+                    .collect();
+                ops.push(Op::Dupe);
+                builder.insert(at, ops);
+                builder.store(&to);
+                to
+            }
+            ExprKind::Neg(exp) => {
+                let ty = exp.visit(builder);
+                if !ty.is_integer() {
+                    builder.error(format!("invalid operand to unary -: {}", ty));
+                }
+                let ty = ty.promote();
+                builder.push(Op::Neg);
+                builder.wrap(&ty);
+                ty
+            }
+            ExprKind::Not(exp) => {
+                exp.visit_condition(builder);
+                builder.push(Op::Push(0));
+                builder.push(Op::Eq);
+                Type::Int
+            }
+            ExprKind::Cast(to, exp) => {
+                let from = exp.visit(builder);
+                if *to != Type::Void && !(from.is_scalar() && to.is_scalar()) {
+                    builder.error(format!("cannot cast {} to {}", from, to));
+                }
+                builder.convert(&from, to);
+                to.clone()
+            }
+            ExprKind::Binary(op, lhs, rhs) => {
+                let lhs_ty = lhs.visit(builder);
+                let rhs_ty = rhs.visit(builder);
+                if lhs_ty.is_pointer() || rhs_ty.is_pointer() {
+                    return builder.pointer_binary(*op, &lhs_ty, &rhs_ty, rhs.is_null());
+                }
+                if !lhs_ty.is_integer() || !rhs_ty.is_integer() {
+                    builder.error(format!(
+                        "invalid operands to {}: {} and {}",
+                        op.symbol(),
+                        lhs_ty,
+                        rhs_ty
+                    ));
+                    return Type::Int;
+                }
+                let ty = Type::common(&lhs_ty, &rhs_ty);
+                builder.convert_second(&lhs_ty, &ty);
+                builder.convert(&rhs_ty, &ty);
+                builder.arithmetic(*op, ty.is_signed());
+                if op.is_comparison() {
+                    return Type::Int;
+                }
+                builder.wrap(&ty);
+                ty
+            }
+            ExprKind::Call(name, args) if name == "__interrupt" => builder.interrupt(args),
+            ExprKind::Call(name, args) => {
+                let signature = builder.symbols.functions.get(name).cloned();
+                match &signature {
+                    Some(signature) if signature.params.len() != args.len() => {
+                        builder.error(format!(
+                            "{} expects {} arguments but got {}",
+                            name,
+                            signature.params.len(),
+                            args.len()
+                        ));
+                    }
+                    Some(_) => {}
+                    // Nothing is known about the parameters, so only the arguments
+                    // themselves are checked.
+                    None => builder.error(format!("call to undeclared function {}", name)),
+                }
+                for (i, arg) in args.iter().enumerate() {
+                    let ty = arg.visit(builder);
+                    if let Some(param) = signature.as_ref().and_then(|s| s.params.get(i)) {
+                        let context = format!("argument {} of {}", i + 1, name);
+                        builder.check_assign(&ty, param, arg, &context);
+                        builder.convert(&ty, param);
+                    }
+                }
+                builder.push_symbol(name);
+                builder.push(Op::Call);
+                signature.map_or(Type::Int, |signature| signature.ret)
+            }
+        }
+    }
+}
+impl<'a> FunctionBuilder<'a> {
+    // Emits an arithmetic or comparison operator on two values of the same integer type.
+    fn arithmetic(&mut self, op: BinaryOp, signed: bool) {
+        let lt = if signed { Op::Lt } else { Op::LtU };
+        match op {
+            BinaryOp::Add => self.push(Op::Add),
+            BinaryOp::Mul => self.push(Op::Mul),
+            BinaryOp::Sub => self.push(Op::Sub),
+            BinaryOp::Div => {
+                self.push(if signed { Op::Div } else { Op::DivU });
+            }
+            BinaryOp::Equal => self.push(Op::Eq),
+            BinaryOp::NotEqual => {
+                self.push(Op::Eq);
+                self.push(Op::Push(0));
+                self.push(Op::Eq);
+            }
+            BinaryOp::Less => self.push(lt),
+            BinaryOp::Greater => {
+                self.push(Op::Swap);
+                self.push(lt);
+            }
+            BinaryOp::LessEqual => {
+                self.push(Op::Swap);
+                self.push(lt);
+                self.push(Op::Push(0));
+                self.push(Op::Eq);
+            }
+            BinaryOp::GreaterEqual => {
+                self.push(lt);
+                self.push(Op::Push(0));
+                self.push(Op::Eq);
+            }
+        }
+    }
+    fn pointer_binary(&mut self, op: BinaryOp, lhs: &Type, rhs: &Type, rhs_null: bool) -> Type {
+        match op {
+            BinaryOp::Add => self.add(lhs, rhs),
+            BinaryOp::Sub => self.sub(lhs, rhs),
+            _ if op.is_comparison() => {
+                let comparable = match (lhs, rhs) {
+                    (Type::Pointer(_), Type::Pointer(_)) => {
+                        lhs == rhs || lhs.is_void_pointer() || rhs.is_void_pointer()
+                    }
+                    // Only equality against a null pointer constant makes sense.
+                    _ => rhs_null && matches!(op, BinaryOp::Equal | BinaryOp::NotEqual),
+                };
+                if !comparable {
+                    self.error(format!("cannot compare {} and {}", lhs, rhs));
+                }
+                self.arithmetic(op, false);
+                Type::Int
+            }
+            _ => {
+                self.error(format!(
+                    "invalid operands to {}: {} and {}",
+                    op.symbol(),
+                    lhs,
+                    rhs
+                ));
+                Type::Int
+            }
+        }
+    }
+    // Emits `lhs + rhs` for the two values on top of the stack, scaling pointer offsets.
+    fn add(&mut self, lhs: &Type, rhs: &Type) -> Type {
+        match (lhs, rhs) {
+            (Type::Pointer(elem), rhs) if rhs.is_integer() => {
+                self.scale(elem);
+                self.push(Op::Add);
+                lhs.clone()
+            }
+            (lhs, Type::Pointer(elem)) if lhs.is_integer() => {
+                self.push(Op::Swap);
+                self.scale(elem);
+                self.push(Op::Add);
+                rhs.clone()
+            }
+            (lhs, rhs) if lhs.is_integer() && rhs.is_integer() => {
+                self.push(Op::Add);
+                Type::common(lhs, rhs)
+            }
+            _ => {
+                self.error(format!("invalid operands to +: {} and {}", lhs, rhs));
+                Type::Int
+            }
+        }
+    }
+    fn sub(&mut self, lhs: &Type, rhs: &Type) -> Type {
+        match (lhs, rhs) {
+            (Type::Pointer(elem), Type::Pointer(_)) => {
+                if lhs != rhs {
+                    self.error(format!("cannot subtract {} from {}", rhs, lhs));
+                }
+                self.push(Op::Sub);
+                self.push(Op::Push(elem.size().max(1) as i64));
+                self.push(Op::Div);
+                Type::Long
+            }
+            (Type::Pointer(elem), rhs) if rhs.is_integer() => {
+                self.scale(elem);
+                self.push(Op::Sub);
+                lhs.clone()
+            }
+            _ => {
+                self.error(format!("invalid operands to -: {} and {}", lhs, rhs));
+                Type::Int
+            }
+        }
+    }
+    fn interrupt(&mut self, args: &[Expression]) -> Type {
+        let Some((number, args)) = args.split_first() else {
+            self.error("__interrupt needs an interrupt number".to_string());
+            return Type::Void;
+        };
+        let interrupt = match number.eval_const() {
+            Ok(number) => INTERRUPTS
+                .iter()
+                .find(|interrupt| interrupt.number as i64 == number),
+            Err(_) => {
+                self.error("interrupt number must be a constant".to_string());
+                return Type::Void;
+            }
+        };
+        let Some(interrupt) = interrupt else {
+            self.error(format!(
+                "unknown interrupt {}",
+                number.eval_const().unwrap()
+            ));
+            return Type::Void;
+        };
+        if args.len() != interrupt.args {
+            self.error(format!(
+                "interrupt {} ({}) expects {} arguments but got {}",
+                interrupt.number,
+                interrupt.name,
+                interrupt.args,
+                args.len()
+            ));
+        }
+        for arg in args {
+            let ty = arg.visit(self);
+            if !ty.is_scalar() {
+                self.error(format!("cannot pass {} to an interrupt", ty));
+            }
+        }
+        self.push(Op::Interrupt(interrupt.number));
+        // Every expression leaves a value behind.
+        self.push(Op::Push(0));
+        Type::Void
+    }
+    // The symbol for a label in inline assembly, which is local to the function.
+    fn asm_label(&self, label: &str) -> Option<String> {
+        let name = label.strip_prefix('.')?;
+        let valid = name.starts_with(|c: char| c.is_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_alphanumeric() || c == '_');
+        valid.then(|| format!(".L{}.{}", self.name, name))
+    }
+    // Lowers the text of an `asm` statement line by line. Instructions are emitted
+    // as written, except `push %name`, which pushes the address of a variable.
+    fn inline_asm(&mut self, text: &str) {
+        for (i, line) in text.lines().enumerate() {
+            // `;` starts a comment in assembly, which in one string would quietly drop
+            // whatever instructions follow it.
+            if line
+                .split_once(';')
+                .is_some_and(|(_, rest)| !rest.trim().is_empty())
+            {
+                self.error(format!(
+                    "inline assembly line {}: text after ; would be ignored",
+                    i + 1
+                ));
+                continue;
+            }
+            let items = match asm::parse_line(line) {
+                Ok(items) => items,
+                Err(message) => {
+                    self.error(format!("inline assembly line {}: {}", i + 1, message));
+                    continue;
+                }
+            };
+            for item in items {
+                match item {
+                    Item::Label(label) => match self.asm_label(&label) {
+                        None => self.error(format!(
+                            "label {} in inline assembly must look like `.name`",
+                            label
+                        )),
+                        Some(_) if self.asm_labels.contains(&label) => {
+                            self.error(format!("label {} defined twice", label))
+                        }
+                        Some(symbol) => {
+                            self.asm_labels.insert(label);
+                            self.push(Op::Label(symbol));
+                        }
+                    },
+                    Item::Instr(instr, None) => self.push(Op::Instruction(instr, 0)),
+                    Item::Instr(instr, Some(Operand::Value(value))) => {
+                        self.push(Op::Instruction(instr, value))
+                    }
+                    Item::Instr(instr, Some(_)) if instr != Instruction::Push => {
+                        self.error(format!("{} takes a number", instr.mnemonic()))
+                    }
+                    Item::Instr(_, Some(Operand::Label(label))) if label.starts_with('.') => {
+                        match self.asm_label(&label) {
+                            Some(symbol) => {
+                                self.asm_references.push((label, self.span));
+                                self.push(Op::Address(symbol));
                             }
-                        },
-                        Token::CloseBrace => {
-                            break;
+                            None => self.error(format!("invalid label {}", label)),
                         }
-                        _ => {
-                            panic!("Unexpected token");
+                    }
+                    Item::Instr(_, Some(Operand::Label(symbol))) => self.push_symbol(&symbol),
+                    Item::Instr(_, Some(Operand::Variable(name))) => {
+                        if let Some(variable) = self.lookup(&name) {
+                            self.push_address(&variable);
                         }
                     }
+                    _ => self.error("directives cannot be used in inline assembly".to_string()),
+                }
+            }
+        }
+    }
+    fn push_size(&mut self, ty: &Type) -> Type {
+        if ty.size() == 0 {
+            self.error(format!("sizeof applied to incomplete type {}", ty));
+        }
+        self.push(Op::Push(ty.size() as i64));
+        Type::ULong
+    }
+    // Adds a member's offset to the struct address on top of the stack.
+    fn member(&mut self, ty: &Type, name: &str) -> Type {
+        let member = match ty {
+            Type::Struct(aggregate) => aggregate.0.borrow().member(name),
+            _ => {
+                self.error(format!("request for member {} in {}", name, ty));
+                return Type::Int;
+            }
+        };
+        match member {
+            Some(member) => {
+                if member.offset != 0 {
+                    self.push(Op::Push(member.offset as i64));
+                    self.push(Op::Add);
+                }
+                member.ty
+            }
+            None => {
+                self.error(format!("{} has no member named {}", ty, name));
+                Type::Int
+            }
+        }
+    }
+    // Multiplies the offset on top of the stack by the size of the pointed-to type.
+    fn scale(&mut self, elem: &Type) {
+        if elem.size() == 0 {
+            self.error(format!("arithmetic on a pointer to {}", elem));
+        }
+        if elem.size() > 1 {
+            self.push(Op::Push(elem.size() as i64));
+            self.push(Op::Mul);
+        }
+    }
+}
+type ParseResult<T> = Result<T, Diagnostic>;
+struct Parser<'a> {
+    tokens: Vec<(Token, Span)>,
+    pos: usize,
+    tags: HashMap<String, AggregateRef>,
+    anonymous: usize,
+    diagnostics: &'a mut Vec<Diagnostic>,
+}
+impl Parser<'_> {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos].0
+    }
+    fn peek_at(&self, n: usize) -> &Token {
+        &self.tokens[(self.pos + n).min(self.tokens.len() - 1)].0
+    }
+    // The span of the next token.
+    fn span(&self) -> Span {
+        self.tokens[self.pos].1
+    }
+    // From `start` to the end of the last token consumed.
+    fn span_from(&self, start: Span) -> Span {
+        match self.pos.checked_sub(1) {
+            Some(last) => start.to(self.tokens[last].1),
+            None => start,
+        }
+    }
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.pos].0.clone();
+        if token != Token::End {
+            self.pos += 1;
+        }
+        token
+    }
+    fn error<T>(&self, span: Span, message: String) -> ParseResult<T> {
+        Err(Diagnostic::error(Some(span), message))
+    }
+    fn unexpected<T>(&self, expected: &str) -> ParseResult<T> {
+        self.error(
+            self.span(),
+            format!("expected {} but got {}", expected, self.peek()),
+        )
+    }
+    fn expect(&mut self, expected: Token) -> ParseResult<()> {
+        if self.eat(expected.clone()) {
+            Ok(())
+        } else {
+            self.unexpected(&expected.to_string())
+        }
+    }
+    fn eat(&mut self, token: Token) -> bool {
+        if *self.peek() == token {
+            self.next();
+            true
+        } else {
+            false
+        }
+    }
+    fn identifier(&mut self) -> ParseResult<String> {
+        match self.peek() {
+            Token::Identifier(name) => {
+                let name = name.clone();
+                self.next();
+                Ok(name)
+            }
+            _ => self.unexpected("identifier"),
+        }
+    }
+    // Skips past the end of a broken statement: the next `;`, or up to the `}` closing its block.
+    fn synchronize(&mut self) {
+        let mut depth = 0;
+        loop {
+            match self.peek() {
+                Token::End => return,
+                Token::CloseBrace if depth == 0 => return,
+                Token::Semicolon if depth == 0 => {
+                    self.next();
+                    return;
+                }
+                Token::OpenBrace => depth += 1,
+                Token::CloseBrace => depth -= 1,
+                _ => {}
+            }
+            self.next();
+        }
+    }
+    // Same as `synchronize` at file scope, where a stray `}` is skipped too.
+    fn synchronize_external(&mut self) {
+        let mut depth = 0;
+        loop {
+            match self.next() {
+                Token::End => return,
+                Token::Semicolon if depth == 0 => return,
+                Token::OpenBrace => depth += 1,
+                Token::CloseBrace if depth <= 1 => return,
+                Token::CloseBrace => depth -= 1,
+                _ => {}
+            }
+        }
+    }
+    fn is_type(token: &Token) -> bool {
+        matches!(
+            token,
+            Token::Void
+                | Token::Char
+                | Token::Short
+                | Token::Int
+                | Token::Long
+                | Token::Signed
+                | Token::Unsigned
+                | Token::Struct
+                | Token::Union
+        )
+    }
+    // Parses a combination of type specifiers such as `unsigned long int`.
+    fn base_type(&mut self) -> ParseResult<Type> {
+        let start = self.span();
+        let mut base = None;
+        let mut signed = None;
+        let mut short = false;
+        let mut longs = 0;
+        if matches!(self.peek(), Token::Struct | Token::Union) {
+            return self.aggregate_type();
+        }
+        while Parser::is_type(self.peek()) {
+            match self.next() {
+                Token::Signed => signed = Some(true),
+                Token::Unsigned => signed = Some(false),
+                Token::Short => short = true,
+                Token::Long => longs += 1,
+                token if base.is_none() => base = Some(token),
+                token => {
+                    return self.error(
+                        self.span_from(start),
+                        format!("unexpected {} in type", token),
+                    )
+                }
+            }
+        }
+        let ty = match (base, short, longs) {
+            (None, false, 0) if signed.is_none() => return self.unexpected("type"),
+            (Some(Token::Void), false, 0) if signed.is_none() => return Ok(Type::Void),
+            (Some(Token::Char), false, 0) => Type::Char,
+            (None | Some(Token::Int), true, 0) => Type::Short,
+            (None | Some(Token::Int), false, 0) => Type::Int,
+            (None | Some(Token::Int), false, 1 | 2) => Type::Long,
+            _ => {
+                return self.error(
+                    self.span_from(start),
+                    "invalid combination of type specifiers".to_string(),
+                )
+            }
+        };
+        if signed == Some(false) {
+            Ok(ty.to_unsigned())
+        } else {
+            Ok(ty)
+        }
+    }
+    // Parses `struct tag`, `struct tag { ... }` or `struct { ... }`, likewise for unions.
+    fn aggregate_type(&mut self) -> ParseResult<Type> {
+        let start = self.span();
+        let is_union = self.next() == Token::Union;
+        let tag = match self.peek() {
+            Token::Identifier(_) => self.identifier()?,
+            _ => {
+                self.anonymous += 1;
+                format!("<anonymous {}>", self.anonymous)
+            }
+        };
+        let aggregate = self
+            .tags
+            .entry(tag.clone())
+            .or_insert_with(|| AggregateRef::new(&tag, is_union))
+            .clone();
+        if aggregate.0.borrow().is_union != is_union {
+            return self.error(
+                self.span_from(start),
+                format!("{} redeclared as a different kind of tag", tag),
+            );
+        }
+        if self.eat(Token::OpenBrace) {
+            let mut members = Vec::new();
+            while !self.eat(Token::CloseBrace) {
+                let base = self.base_type()?;
+                loop {
+                    let member_start = self.span();
+                    let mut ty = self.pointers(base.clone());
+                    let name = self.identifier()?;
+                    if self.eat(Token::OpenBracket) {
+                        let len = self.array_size(&ty)?;
+                        ty = Type::Array(Box::new(ty), len);
+                        self.expect(Token::CloseBracket)?;
+                    }
+                    // This includes the aggregate being defined, which can't be sized yet.
+                    // The member is left out so the rest of the definition still parses.
+                    if ty.is_complete() {
+                        members.push((ty, name));
+                    } else {
+                        let message = format!("member {} has incomplete type {}", name, ty);
+                        self.diagnostics.push(Diagnostic::error(
+                            Some(self.span_from(member_start)),
+                            message,
+                        ));
+                    }
+                    if !self.eat(Token::Comma) {
+                        break;
+                    }
+                }
+                self.expect(Token::Semicolon)?;
+            }
+            if let Err(message) = aggregate.0.borrow_mut().define(members) {
+                return self.error(self.span_from(start), message);
+            }
+        }
+        Ok(Type::Struct(aggregate))
+    }
+    // The number of `elem`s in an array, which must fit in the VM's memory.
+    fn array_size(&mut self, elem: &Type) -> ParseResult<usize> {
+        match self.peek() {
+            Token::Integer(num) if *num > 0 => {
+                let len = *num as usize;
+                let span = self.span();
+                self.next();
+                // One element keeps the type usable so the rest still gets checked.
+                if elem
+                    .size()
+                    .checked_mul(len)
+                    .is_none_or(|size| size > MEMORY_SIZE)
+                {
+                    let message = "array is too large".to_string();
+                    self.diagnostics
+                        .push(Diagnostic::error(Some(span), message));
+                    return Ok(1);
+                }
+                Ok(len)
+            }
+            _ => self.unexpected("array size"),
+        }
+    }
+    fn pointers(&mut self, base: Type) -> Type {
+        let mut ty = base;
+        while self.eat(Token::Star) {
+            ty = Type::Pointer(Box::new(ty));
+        }
+        ty
+    }
+    // Parses a base type followed by any number of `*`.
+    fn declared_type(&mut self) -> ParseResult<Type> {
+        let base = self.base_type()?;
+        Ok(self.pointers(base))
+    }
+    fn external_declaration(&mut self) -> ParseResult<Option<ASTNode>> {
+        // `static` at file scope only limits linkage, which a single file doesn't have.
+        let is_static = self.eat(Token::Static);
+        let ty = self.declared_type()?;
+        // A declaration like `struct s { ... };` only introduces the tag.
+        if self.eat(Token::Semicolon) {
+            return Ok(None);
+        }
+        let span = self.span();
+        let name = self.identifier()?;
+        if self.eat(Token::OpenParen) {
+            return Ok(Some(self.function(name, ty, span)?));
+        }
+        Ok(Some(ASTNode::GlobalDecl(
+            self.declaration_rest(name, ty, is_static, span)?,
+        )))
+    }
+    fn function(&mut self, name: String, ret: Type, span: Span) -> ParseResult<ASTNode> {
+        let mut params = Vec::new();
+        if *self.peek() == Token::Void && *self.peek_at(1) == Token::CloseParen {
+            self.next();
+        }
+        if !self.eat(Token::CloseParen) {
+            loop {
+                let mut ty = self.declared_type()?;
+                let name = self.identifier()?;
+                // Array parameters are really pointers.
+                if self.eat(Token::OpenBracket) {
+                    self.expect(Token::CloseBracket)?;
+                    ty = Type::Pointer(Box::new(ty));
+                }
+                params.push((ty, name));
+                if !self.eat(Token::Comma) {
+                    break;
+                }
+            }
+            self.expect(Token::CloseParen)?;
+        }
+        if self.eat(Token::Semicolon) {
+            return Ok(ASTNode::FunDecl(name, ret, params, None, span));
+        }
+        let body = self.block()?;
+        Ok(ASTNode::FunDecl(name, ret, params, Some(body), span))
+    }
+    fn declaration(&mut self) -> ParseResult<Statement> {
+        let is_static = self.eat(Token::Static);
+        let ty = self.declared_type()?;
+        if self.eat(Token::Semicolon) {
+            return Ok(Statement::Empty);
+        }
+        let span = self.span();
+        let name = self.identifier()?;
+        Ok(Statement::Declare(
+            self.declaration_rest(name, ty, is_static, span)?,
+        ))
+    }
+    // Parses an optional array size and initializer after the declared name.
+    fn declaration_rest(
+        &mut self,
+        name: String,
+        ty: Type,
+        is_static: bool,
+        span: Span,
+    ) -> ParseResult<Declaration> {
+        let mut ty = ty;
+        let mut len = None;
+        let is_array = self.eat(Token::OpenBracket);
+        if is_array && !self.eat(Token::CloseBracket) {
+            len = Some(self.array_size(&ty)?);
+            self.expect(Token::CloseBracket)?;
+        }
+        let init = if !self.eat(Token::Assign) {
+            None
+        } else if self.eat(Token::OpenBrace) {
+            let mut exps = Vec::new();
+            while !self.eat(Token::CloseBrace) {
+                exps.push(self.expression()?);
+                if !self.eat(Token::Comma) {
+                    self.expect(Token::CloseBrace)?;
+                    break;
                 }
-                assert_eq!(tokens.remove(0), Token::CloseBrace);
-                ast = ASTNode::Prog(Box::new(ASTNode::FunDecl(name, statements)));
             }
-            Token::End => {
-                return ast;
+            Some(Initializer::List(exps))
+        } else {
+            Some(Initializer::Expr(self.expression()?))
+        };
+        self.expect(Token::Semicolon)?;
+        // A char array initialized from a string gets its bytes, nul included if it fits.
+        let init = match init {
+            Some(Initializer::Expr(Expression {
+                kind: ExprKind::Str(string),
+                span: str_span,
+            })) if is_array && ty.is_integer() && ty.size() == 1 => {
+                let mut exps: Vec<_> = string
+                    .into_iter()
+                    .map(|c| Expression::new(ExprKind::Num(c as i64), str_span))
+                    .collect();
+                if len != Some(exps.len()) {
+                    exps.push(Expression::new(ExprKind::Num(0), str_span));
+                }
+                Some(Initializer::List(exps))
+            }
+            init => init,
+        };
+        if is_array {
+            let len = match (len, &init) {
+                (Some(len), _) => len,
+                (None, Some(Initializer::List(exps))) if !exps.is_empty() => exps.len(),
+                _ => return self.error(span, format!("array {} needs a size", name)),
+            };
+            ty = Type::Array(Box::new(ty), len);
+        }
+        Ok(Declaration {
+            name,
+            ty,
+            init,
+            is_static,
+            span,
+        })
+    }
+    // Parses a braced block. Broken statements are reported and skipped.
+    fn block(&mut self) -> ParseResult<Vec<Statement>> {
+        self.expect(Token::OpenBrace)?;
+        let mut statements = Vec::new();
+        while !self.eat(Token::CloseBrace) {
+            if *self.peek() == Token::End {
+                return self.unexpected("`}`");
+            }
+            match self.statement() {
+                Ok(statement) => statements.push(statement),
+                Err(diagnostic) => {
+                    self.diagnostics.push(diagnostic);
+                    self.synchronize();
+                }
+            }
+        }
+        Ok(statements)
+    }
+    fn statement(&mut self) -> ParseResult<Statement> {
+        let start = self.span();
+        match self.peek() {
+            Token::Return => {
+                self.next();
+                if self.eat(Token::Semicolon) {
+                    return Ok(Statement::Return(None, self.span_from(start)));
+                }
+                let exp = self.expression()?;
+                self.expect(Token::Semicolon)?;
+                Ok(Statement::Return(Some(exp), self.span_from(start)))
+            }
+            token if Parser::is_type(token) || *token == Token::Static => self.declaration(),
+            Token::If => {
+                self.next();
+                self.expect(Token::OpenParen)?;
+                let cond = self.expression()?;
+                self.expect(Token::CloseParen)?;
+                let then = Box::new(self.statement()?);
+                let otherwise = if self.eat(Token::Else) {
+                    Some(Box::new(self.statement()?))
+                } else {
+                    None
+                };
+                Ok(Statement::If(cond, then, otherwise))
+            }
+            Token::While => {
+                self.next();
+                self.expect(Token::OpenParen)?;
+                let cond = self.expression()?;
+                self.expect(Token::CloseParen)?;
+                Ok(Statement::While(cond, Box::new(self.statement()?)))
+            }
+            Token::Asm => {
+                self.next();
+                self.expect(Token::OpenParen)?;
+                let mut text = Vec::new();
+                while let Token::Str(string) = self.peek() {
+                    text.extend_from_slice(string);
+                    self.next();
+                }
+                if text.is_empty() && *self.peek() != Token::CloseParen {
+                    return self.unexpected("string literal");
+                }
+                self.expect(Token::CloseParen)?;
+                self.expect(Token::Semicolon)?;
+                let text = String::from_utf8_lossy(&text).into_owned();
+                Ok(Statement::Asm(text, self.span_from(start)))
+            }
+            Token::OpenBrace => Ok(Statement::Block(self.block()?)),
+            Token::Semicolon => {
+                self.next();
+                Ok(Statement::Empty)
             }
             _ => {
-                panic!("Unexpected token");
+                let exp = self.expression()?;
+                self.expect(Token::Semicolon)?;
+                Ok(Statement::Exp(exp))
+            }
+        }
+    }
+    fn expression(&mut self) -> ParseResult<Expression> {
+        let lhs = self.binary(0)?;
+        if self.eat(Token::Assign) {
+            let rhs = self.expression()?;
+            let span = lhs.span.to(rhs.span);
+            return Ok(Expression::new(
+                ExprKind::Assign(Box::new(lhs), Box::new(rhs)),
+                span,
+            ));
+        }
+        Ok(lhs)
+    }
+    // Parses binary operators by precedence level, lowest first.
+    fn binary(&mut self, level: usize) -> ParseResult<Expression> {
+        const LEVELS: [&[(Token, BinaryOp)]; 4] = [
+            &[
+                (Token::Equal, BinaryOp::Equal),
+                (Token::NotEqual, BinaryOp::NotEqual),
+            ],
+            &[
+                (Token::Less, BinaryOp::Less),
+                (Token::LessEqual, BinaryOp::LessEqual),
+                (Token::Greater, BinaryOp::Greater),
+                (Token::GreaterEqual, BinaryOp::GreaterEqual),
+            ],
+            &[(Token::Plus, BinaryOp::Add), (Token::Minus, BinaryOp::Sub)],
+            &[(Token::Star, BinaryOp::Mul), (Token::Slash, BinaryOp::Div)],
+        ];
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        while let Some((_, op)) = LEVELS[level].iter().find(|(token, _)| token == self.peek()) {
+            self.next();
+            let rhs = self.binary(level + 1)?;
+            let span = lhs.span.to(rhs.span);
+            lhs = Expression::new(ExprKind::Binary(*op, Box::new(lhs), Box::new(rhs)), span);
+        }
+        Ok(lhs)
+    }
+    fn unary(&mut self) -> ParseResult<Expression> {
+        let start = self.span();
+        let kind = match self.peek() {
+            Token::Minus => {
+                self.next();
+                ExprKind::Neg(Box::new(self.unary()?))
+            }
+            Token::Bang => {
+                self.next();
+                ExprKind::Not(Box::new(self.unary()?))
+            }
+            Token::Star => {
+                self.next();
+                ExprKind::Deref(Box::new(self.unary()?))
+            }
+            Token::Ampersand => {
+                self.next();
+                ExprKind::AddrOf(Box::new(self.unary()?))
+            }
+            Token::Sizeof => {
+                self.next();
+                if *self.peek() == Token::OpenParen && Parser::is_type(self.peek_at(1)) {
+                    self.next();
+                    let ty = self.declared_type()?;
+                    self.expect(Token::CloseParen)?;
+                    ExprKind::SizeofType(ty)
+                } else {
+                    ExprKind::SizeofExpr(Box::new(self.unary()?))
+                }
+            }
+            Token::OpenParen if Parser::is_type(self.peek_at(1)) => {
+                self.next();
+                let ty = self.declared_type()?;
+                self.expect(Token::CloseParen)?;
+                ExprKind::Cast(ty, Box::new(self.unary()?))
+            }
+            _ => return self.postfix(),
+        };
+        Ok(Expression::new(kind, self.span_from(start)))
+    }
+    fn postfix(&mut self) -> ParseResult<Expression> {
+        let start = self.span();
+        let mut exp = self.primary()?;
+        loop {
+            let kind = match self.peek() {
+                Token::OpenBracket => {
+                    self.next();
+                    let index = self.expression()?;
+                    self.expect(Token::CloseBracket)?;
+                    ExprKind::Index(Box::new(exp), Box::new(index))
+                }
+                Token::Dot => {
+                    self.next();
+                    ExprKind::Member(Box::new(exp), self.identifier()?)
+                }
+                Token::Arrow => {
+                    self.next();
+                    ExprKind::Arrow(Box::new(exp), self.identifier()?)
+                }
+                _ => return Ok(exp),
+            };
+            exp = Expression::new(kind, self.span_from(start));
+        }
+    }
+    fn primary(&mut self) -> ParseResult<Expression> {
+        let start = self.span();
+        let kind = match self.peek().clone() {
+            Token::Integer(num) => {
+                self.next();
+                ExprKind::Num(num)
+            }
+            Token::Str(mut string) => {
+                self.next();
+                // Adjacent literals are concatenated.
+                while let Token::Str(next) = self.peek() {
+                    string.extend_from_slice(next);
+                    self.next();
+                }
+                ExprKind::Str(string)
+            }
+            Token::Identifier(name) => {
+                self.next();
+                if !self.eat(Token::OpenParen) {
+                    return Ok(Expression::new(ExprKind::Var(name), start));
+                }
+                let mut args = Vec::new();
+                if !self.eat(Token::CloseParen) {
+                    loop {
+                        args.push(self.expression()?);
+                        if !self.eat(Token::Comma) {
+                            break;
+                        }
+                    }
+                    self.expect(Token::CloseParen)?;
+                }
+                ExprKind::Call(name, args)
+            }
+            Token::OpenParen => {
+                self.next();
+                let exp = self.expression()?;
+                self.expect(Token::CloseParen)?;
+                return Ok(exp);
+            }
+            _ => return self.unexpected("expression"),
+        };
+        Ok(Expression::new(kind, self.span_from(start)))
+    }
+}
+pub fn parse(tokens: Vec<(Token, Span)>, diagnostics: &mut Vec<Diagnostic>) -> ASTNode {
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        tags: HashMap::new(),
+        anonymous: 0,
+        diagnostics,
+    };
+    let mut decls = Vec::new();
+    while *parser.peek() != Token::End {
+        match parser.external_declaration() {
+            Ok(decl) => decls.extend(decl),
+            Err(diagnostic) => {
+                parser.diagnostics.push(diagnostic);
+                parser.synchronize_external();
             }
         }
     }
-    ast
+    ASTNode::Prog(decls)
 }
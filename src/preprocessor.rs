@@ -0,0 +1,442 @@
+use super::diagnostic::{Diagnostic, Source, Span};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// Headers that are always available, even when nothing is found next to the source.
+const BUILTIN_HEADERS: [(&str, &str); 3] = [
+    ("badvm.h", include_str!("../include/badvm.h")),
+    ("string.h", include_str!("../include/string.h")),
+    ("stdlib.h", include_str!("../include/stdlib.h")),
+];
+const MAX_INCLUDE_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Macro {
+    // None for object-like macros.
+    params: Option<Vec<String>>,
+    body: String,
+}
+// One level of #ifdef/#ifndef nesting.
+struct Conditional {
+    active: bool,
+    seen_else: bool,
+}
+struct Preprocessor<'a> {
+    macros: HashMap<String, Macro>,
+    source: Source,
+    diagnostics: &'a mut Vec<Diagnostic>,
+    depth: usize,
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+fn is_ident(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+// Copies a string or character literal starting at `chars[i]`, returning the index after it.
+fn skip_literal(chars: &[char], mut i: usize, out: &mut String) -> usize {
+    let quote = chars[i];
+    out.push(quote);
+    i += 1;
+    while i < chars.len() && chars[i] != quote && chars[i] != '\n' {
+        if chars[i] == '\\' && i + 1 < chars.len() {
+            out.push(chars[i]);
+            i += 1;
+        }
+        out.push(chars[i]);
+        i += 1;
+    }
+    if i < chars.len() && chars[i] == quote {
+        out.push(quote);
+        i += 1;
+    }
+    i
+}
+fn identifier(chars: &[char], mut i: usize) -> (String, usize) {
+    let start = i;
+    while i < chars.len() && is_ident(chars[i]) {
+        i += 1;
+    }
+    (chars[start..i].iter().collect(), i)
+}
+// Joins lines ending in a backslash. The newlines removed are added back after
+// the joined line so the lines that follow keep their numbers.
+fn join_lines(code: &str) -> String {
+    let mut out = String::with_capacity(code.len());
+    let mut joined = 0;
+    for line in code.lines() {
+        match line.strip_suffix('\\') {
+            Some(line) => {
+                out.push_str(line);
+                joined += 1;
+            }
+            None => {
+                out.push_str(line);
+                out.push_str(&"\n".repeat(joined + 1));
+                joined = 0;
+            }
+        }
+    }
+    out
+}
+// Removes comments. Newlines are kept so line numbers stay put.
+fn strip_comments(code: &str) -> Result<String, String> {
+    let chars: Vec<char> = join_lines(code).chars().collect();
+    let mut out = String::with_capacity(chars.len());
+    let mut i = 0;
+    while i < chars.len() {
+        match (chars[i], chars.get(i + 1)) {
+            ('"' | '\'', _) => i = skip_literal(&chars, i, &mut out),
+            ('/', Some('/')) => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            }
+            ('/', Some('*')) => {
+                out.push(' ');
+                i += 2;
+                loop {
+                    match (chars.get(i), chars.get(i + 1)) {
+                        (None, _) => return Err("unterminated comment".to_string()),
+                        (Some('*'), Some('/')) => break,
+                        (Some('\n'), _) => out.push('\n'),
+                        _ => {}
+                    }
+                    i += 1;
+                }
+                i += 2;
+            }
+            (c, _) => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+    Ok(out)
+}
+impl Preprocessor<'_> {
+    // Reports a problem at the current end of the output.
+    fn error(&mut self, message: String) {
+        self.error_at(self.source.text.len(), message);
+    }
+    fn error_at(&mut self, at: usize, message: String) {
+        self.diagnostics
+            .push(Diagnostic::error(Some(Span::new(at, at)), message));
+    }
+    // Ends an output line that came from `line` of `file`.
+    fn newline(&mut self, file: usize, line: usize) {
+        self.source.text.push('\n');
+        self.source.add_line(file, line);
+    }
+    fn file(&mut self, code: &str, path: &Path) {
+        let file = self.source.add_file(path, code);
+        let code = match strip_comments(code) {
+            Ok(code) => code,
+            Err(message) => {
+                self.error(format!("{} in {}", message, path.display()));
+                return;
+            }
+        };
+        let mut conditionals: Vec<Conditional> = Vec::new();
+        // Text between directives, expanded in one go so macro calls can span lines.
+        let mut pending = String::new();
+        let mut pending_line = 1;
+        for (index, line) in code.lines().enumerate() {
+            let number = index + 1;
+            let skipping = conditionals.iter().any(|cond| !cond.active);
+            let directive = match line.trim_start().strip_prefix('#') {
+                Some(directive) => directive.trim(),
+                None => {
+                    if skipping {
+                        self.newline(file, number);
+                    } else {
+                        if pending.is_empty() {
+                            pending_line = number;
+                        }
+                        pending.push_str(line);
+                        pending.push('\n');
+                    }
+                    continue;
+                }
+            };
+            self.flush(&mut pending, file, pending_line);
+            let (name, rest) = match directive.find(|c: char| !is_ident(c)) {
+                Some(end) => (&directive[..end], directive[end..].trim()),
+                None => (directive, ""),
+            };
+            let result = match name {
+                "ifdef" | "ifndef" => {
+                    let defined = self.macros.contains_key(rest);
+                    conditionals.push(Conditional {
+                        active: defined == (name == "ifdef"),
+                        seen_else: false,
+                    });
+                    Ok(())
+                }
+                "else" => match conditionals.last_mut() {
+                    Some(cond) if !cond.seen_else => {
+                        cond.active = !cond.active;
+                        cond.seen_else = true;
+                        Ok(())
+                    }
+                    Some(_) => Err("#else after #else".to_string()),
+                    None => Err("#else without #ifdef".to_string()),
+                },
+                "endif" => match conditionals.pop() {
+                    Some(_) => Ok(()),
+                    None => Err("#endif without #ifdef".to_string()),
+                },
+                _ if skipping => Ok(()),
+                "define" => self.define(rest),
+                "undef" => {
+                    self.macros.remove(rest);
+                    Ok(())
+                }
+                "include" => self.include(rest, path),
+                "" => Ok(()),
+                _ => Err(format!("unknown directive #{}", name)),
+            };
+            // The directive's line is left blank, after anything it included.
+            if let Err(message) = result {
+                self.error(message);
+            }
+            self.newline(file, number);
+        }
+        self.flush(&mut pending, file, pending_line);
+        if !conditionals.is_empty() {
+            self.error(format!("unterminated #ifdef in {}", path.display()));
+        }
+    }
+    fn flush(&mut self, pending: &mut String, file: usize, line: usize) {
+        if pending.is_empty() {
+            return;
+        }
+        let text = match self.expand(pending, &mut Vec::new()) {
+            Ok(text) => text,
+            Err((index, message)) => {
+                // The text goes out unexpanded, so the call is found at the same offset.
+                let offset = pending.char_indices().nth(index).map_or(0, |(i, _)| i);
+                self.error_at(self.source.text.len() + offset, message);
+                pending.clone()
+            }
+        };
+        for (i, line_text) in text.lines().enumerate() {
+            self.source.text.push_str(line_text);
+            self.newline(file, line + i);
+        }
+        pending.clear();
+    }
+    fn define(&mut self, rest: &str) -> Result<(), String> {
+        let chars: Vec<char> = rest.chars().collect();
+        if chars.first().is_none_or(|c| !is_ident_start(*c)) {
+            return Err("macro name must be an identifier".to_string());
+        }
+        let (name, mut i) = identifier(&chars, 0);
+        // Only a parenthesis directly after the name makes a function-like macro.
+        let params = if chars.get(i) == Some(&'(') {
+            let end = match chars[i..].iter().position(|c| *c == ')') {
+                Some(end) => i + end,
+                None => return Err(format!("missing ) in parameters of {}", name)),
+            };
+            let list: String = chars[i + 1..end].iter().collect();
+            i = end + 1;
+            let params: Vec<String> = if list.trim().is_empty() {
+                Vec::new()
+            } else {
+                list.split(',')
+                    .map(|param| param.trim().to_string())
+                    .collect()
+            };
+            if params
+                .iter()
+                .any(|param| !param.starts_with(is_ident_start) || !param.chars().all(is_ident))
+            {
+                return Err(format!("invalid parameter list for {}", name));
+            }
+            Some(params)
+        } else {
+            None
+        };
+        let body = chars[i..].iter().collect::<String>().trim().to_string();
+        let new = Macro { params, body };
+        match self.macros.get(&name) {
+            Some(old) if *old != new => Err(format!("{} redefined", name)),
+            _ => {
+                self.macros.insert(name, new);
+                Ok(())
+            }
+        }
+    }
+    fn include(&mut self, rest: &str, path: &Path) -> Result<(), String> {
+        let name = match rest
+            .strip_prefix('"')
+            .and_then(|rest| rest.strip_suffix('"'))
+        {
+            Some(name) => name,
+            None => return Err("#include expects \"file\"".to_string()),
+        };
+        if self.depth == MAX_INCLUDE_DEPTH {
+            return Err("#include nested too deeply".to_string());
+        }
+        let included = path.parent().unwrap_or(Path::new("")).join(name);
+        let code = match fs::read_to_string(&included) {
+            Ok(code) => code,
+            Err(err) => match BUILTIN_HEADERS.iter().find(|(header, _)| *header == name) {
+                Some((_, code)) => code.to_string(),
+                None => return Err(format!("cannot include {}: {}", name, err)),
+            },
+        };
+        self.depth += 1;
+        self.file(&code, &included);
+        self.depth -= 1;
+        Ok(())
+    }
+    // Replaces macros in `text`. Macros in `active` are being expanded and are left alone.
+    // Errors come with the index of the character in `text` where the failing call starts.
+    fn expand(&self, text: &str, active: &mut Vec<String>) -> Result<String, (usize, String)> {
+        let chars: Vec<char> = text.chars().collect();
+        let mut out = String::with_capacity(text.len());
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            if c == '"' || c == '\'' {
+                i = skip_literal(&chars, i, &mut out);
+                continue;
+            }
+            if c.is_ascii_digit() {
+                // Numbers like 0x1f or 10u must not have their tail taken for an identifier.
+                while i < chars.len() && (is_ident(chars[i]) || chars[i] == '.') {
+                    out.push(chars[i]);
+                    i += 1;
+                }
+                continue;
+            }
+            if !is_ident_start(c) {
+                out.push(c);
+                i += 1;
+                continue;
+            }
+            let start = i;
+            let (name, end) = identifier(&chars, i);
+            i = end;
+            let mac = match self.macros.get(&name) {
+                Some(mac) if !active.contains(&name) => mac,
+                _ => {
+                    out.push_str(&name);
+                    continue;
+                }
+            };
+            let body = match &mac.params {
+                None => mac.body.clone(),
+                Some(params) => {
+                    let mut open = i;
+                    while open < chars.len() && chars[open].is_whitespace() {
+                        open += 1;
+                    }
+                    // A function-like macro name without arguments is just an identifier.
+                    if chars.get(open) != Some(&'(') {
+                        out.push_str(&name);
+                        continue;
+                    }
+                    let (args, end) = Preprocessor::arguments(&chars, open + 1)
+                        .ok_or((start, format!("unterminated call to macro {}", name)))?;
+                    let newlines = chars[i..end].iter().filter(|c| **c == '\n').count();
+                    i = end;
+                    let args = if params.is_empty() && args.len() == 1 && args[0].trim().is_empty()
+                    {
+                        Vec::new()
+                    } else {
+                        args
+                    };
+                    if args.len() != params.len() {
+                        let message = format!(
+                            "macro {} takes {} arguments but {} were given",
+                            name,
+                            params.len(),
+                            args.len()
+                        );
+                        return Err((start, message));
+                    }
+                    let mut expanded = HashMap::new();
+                    for (param, arg) in params.iter().zip(args) {
+                        let arg = self
+                            .expand(arg.trim(), active)
+                            .map_err(|(_, message)| (start, message))?;
+                        expanded.insert(param.as_str(), arg);
+                    }
+                    let body = Preprocessor::substitute(&mac.body, &expanded);
+                    // Keep the lines the call spanned so later line numbers stay right.
+                    body + &"\n".repeat(newlines)
+                }
+            };
+            active.push(name);
+            let result = self.expand(&body, active);
+            active.pop();
+            out.push_str(&result.map_err(|(_, message)| (start, message))?);
+        }
+        Ok(out)
+    }
+    // Splits macro arguments at top-level commas, returning them and the index after `)`.
+    fn arguments(chars: &[char], mut i: usize) -> Option<(Vec<String>, usize)> {
+        let mut args = Vec::new();
+        let mut arg = String::new();
+        let mut depth = 0;
+        loop {
+            match *chars.get(i)? {
+                '"' | '\'' => {
+                    i = skip_literal(chars, i, &mut arg);
+                    continue;
+                }
+                '(' => depth += 1,
+                ')' if depth == 0 => {
+                    args.push(arg);
+                    return Some((args, i + 1));
+                }
+                ')' => depth -= 1,
+                ',' if depth == 0 => {
+                    args.push(std::mem::take(&mut arg));
+                    i += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            arg.push(chars[i]);
+            i += 1;
+        }
+    }
+    fn substitute(body: &str, args: &HashMap<&str, String>) -> String {
+        let chars: Vec<char> = body.chars().collect();
+        let mut out = String::with_capacity(body.len());
+        let mut i = 0;
+        while i < chars.len() {
+            if chars[i] == '"' || chars[i] == '\'' {
+                i = skip_literal(&chars, i, &mut out);
+            } else if chars[i].is_ascii_digit() {
+                while i < chars.len() && (is_ident(chars[i]) || chars[i] == '.') {
+                    out.push(chars[i]);
+                    i += 1;
+                }
+            } else if is_ident_start(chars[i]) {
+                let (name, end) = identifier(&chars, i);
+                out.push_str(args.get(name.as_str()).unwrap_or(&name));
+                i = end;
+            } else {
+                out.push(chars[i]);
+                i += 1;
+            }
+        }
+        out
+    }
+}
+pub fn preprocess(code: &str, path: &Path, diagnostics: &mut Vec<Diagnostic>) -> Source {
+    let mut preprocessor = Preprocessor {
+        macros: HashMap::new(),
+        source: Source::default(),
+        diagnostics,
+        depth: 0,
+    };
+    preprocessor.file(code, path);
+    preprocessor.source
+}
@@ -0,0 +1,288 @@
+use super::ir::Op;
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+// Values are always kept sign or zero extended to 64 bits on the VM stack,
+// so only memory accesses and narrowing conversions care about widths.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Type {
+    Void,
+    Char,
+    UChar,
+    Short,
+    UShort,
+    Int,
+    UInt,
+    Long,
+    ULong,
+    Pointer(Box<Type>),
+    Array(Box<Type>, usize),
+    Struct(AggregateRef),
+}
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub name: String,
+    pub ty: Type,
+    pub offset: usize,
+}
+// A struct or union. It stays incomplete until its member list has been seen.
+#[derive(Debug)]
+pub struct Aggregate {
+    pub tag: String,
+    pub is_union: bool,
+    pub members: Vec<Member>,
+    pub size: usize,
+    pub align: usize,
+    pub complete: bool,
+}
+impl Aggregate {
+    // The members must have complete types.
+    pub fn define(&mut self, members: Vec<(Type, String)>) -> Result<(), String> {
+        if self.complete {
+            return Err(format!("redefinition of {}", self.keyword()));
+        }
+        let mut offset: usize = 0;
+        for (ty, name) in members {
+            if self.members.iter().any(|member| member.name == name) {
+                return Err(format!("duplicate member {}", name));
+            }
+            let align = ty.align();
+            self.align = self.align.max(align);
+            if !self.is_union {
+                offset = offset.div_ceil(align) * align;
+            }
+            self.size = self.size.max(offset + ty.size());
+            self.members.push(Member { name, ty, offset });
+            if !self.is_union {
+                offset += self.members.last().unwrap().ty.size();
+            }
+        }
+        self.size = self.size.div_ceil(self.align) * self.align;
+        self.complete = true;
+        Ok(())
+    }
+    pub fn member(&self, name: &str) -> Option<Member> {
+        self.members
+            .iter()
+            .find(|member| member.name == name)
+            .cloned()
+    }
+    fn keyword(&self) -> String {
+        let keyword = if self.is_union { "union" } else { "struct" };
+        format!("{} {}", keyword, self.tag)
+    }
+}
+// Aggregates are compared by identity, like C struct tags.
+#[derive(Clone)]
+pub struct AggregateRef(pub Rc<RefCell<Aggregate>>);
+impl AggregateRef {
+    pub fn new(tag: &str, is_union: bool) -> AggregateRef {
+        AggregateRef(Rc::new(RefCell::new(Aggregate {
+            tag: tag.to_string(),
+            is_union,
+            members: Vec::new(),
+            size: 0,
+            align: 1,
+            complete: false,
+        })))
+    }
+}
+impl PartialEq for AggregateRef {
+    fn eq(&self, other: &AggregateRef) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+impl Eq for AggregateRef {}
+impl fmt::Debug for AggregateRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.borrow().keyword())
+    }
+}
+impl Type {
+    pub fn size(&self) -> usize {
+        match self {
+            Type::Void => 0,
+            Type::Char | Type::UChar => 1,
+            Type::Short | Type::UShort => 2,
+            Type::Int | Type::UInt => 4,
+            Type::Long | Type::ULong | Type::Pointer(_) => 8,
+            Type::Array(elem, len) => elem.size() * len,
+            Type::Struct(aggregate) => aggregate.0.borrow().size,
+        }
+    }
+    // Void and structs or unions whose members have not been seen have no size.
+    pub fn is_complete(&self) -> bool {
+        match self {
+            Type::Void => false,
+            Type::Array(elem, _) => elem.is_complete(),
+            Type::Struct(aggregate) => aggregate.0.borrow().complete,
+            _ => true,
+        }
+    }
+    pub fn align(&self) -> usize {
+        match self {
+            Type::Array(elem, _) => elem.align(),
+            Type::Struct(aggregate) => aggregate.0.borrow().align,
+            ty => ty.size().max(1),
+        }
+    }
+    pub fn is_integer(&self) -> bool {
+        matches!(
+            self,
+            Type::Char
+                | Type::UChar
+                | Type::Short
+                | Type::UShort
+                | Type::Int
+                | Type::UInt
+                | Type::Long
+                | Type::ULong
+        )
+    }
+    pub fn is_signed(&self) -> bool {
+        matches!(self, Type::Char | Type::Short | Type::Int | Type::Long)
+    }
+    pub fn is_pointer(&self) -> bool {
+        matches!(self, Type::Pointer(_))
+    }
+    pub fn is_scalar(&self) -> bool {
+        self.is_integer() || self.is_pointer()
+    }
+    pub fn is_void_pointer(&self) -> bool {
+        matches!(self, Type::Pointer(ty) if **ty == Type::Void)
+    }
+    pub fn decay(self) -> Type {
+        match self {
+            Type::Array(elem, _) => Type::Pointer(elem),
+            ty => ty,
+        }
+    }
+    pub fn to_unsigned(&self) -> Type {
+        match self {
+            Type::Char => Type::UChar,
+            Type::Short => Type::UShort,
+            Type::Int => Type::UInt,
+            Type::Long => Type::ULong,
+            ty => ty.clone(),
+        }
+    }
+    // Integer promotion: everything narrower than int computes as int.
+    pub fn promote(&self) -> Type {
+        match self {
+            Type::Char | Type::UChar | Type::Short | Type::UShort => Type::Int,
+            ty => ty.clone(),
+        }
+    }
+    // The usual arithmetic conversions for a binary operator on two integers.
+    pub fn common(lhs: &Type, rhs: &Type) -> Type {
+        let lhs = lhs.promote();
+        let rhs = rhs.promote();
+        if lhs == rhs {
+            return lhs;
+        }
+        if lhs.size() == rhs.size() {
+            return lhs.to_unsigned();
+        }
+        let (wide, narrow) = if lhs.size() > rhs.size() {
+            (lhs, rhs)
+        } else {
+            (rhs, lhs)
+        };
+        if wide.contains(&narrow) {
+            wide
+        } else {
+            wide.to_unsigned()
+        }
+    }
+    // Whether every value of the integer type `other` can be represented in `self`.
+    pub fn contains(&self, other: &Type) -> bool {
+        match (self.is_signed(), other.is_signed()) {
+            (true, true) | (false, false) => self.size() >= other.size(),
+            (true, false) => self.size() > other.size(),
+            (false, true) => false,
+        }
+    }
+    // Wraps a constant into the range of this type.
+    pub fn wrap(&self, value: i64) -> i64 {
+        if !self.is_integer() || self.size() == 8 {
+            return value;
+        }
+        let shift = 64 - self.size() as u32 * 8;
+        if self.is_signed() {
+            value.wrapping_shl(shift).wrapping_shr(shift)
+        } else {
+            ((value as u64).wrapping_shl(shift).wrapping_shr(shift)) as i64
+        }
+    }
+    // Whether a constant fits in the type as either a signed or an unsigned value,
+    // so `unsigned char c = -1` is fine but `char c = 300` is not.
+    pub fn fits(&self, value: i64) -> bool {
+        if !self.is_integer() || self.size() == 8 {
+            return true;
+        }
+        let bits = self.size() as u32 * 8;
+        value >= -(1 << (bits - 1)) && value < (1 << bits)
+    }
+    // The offset and type of each element an initializer list fills, in order.
+    pub fn elements(&self) -> Option<Vec<(usize, Type)>> {
+        match self {
+            Type::Array(elem, len) => Some(
+                (0..*len)
+                    .map(|i| (i * elem.size(), (**elem).clone()))
+                    .collect(),
+            ),
+            Type::Struct(aggregate) => {
+                let aggregate = aggregate.0.borrow();
+                // Only the first member of a union can be initialized.
+                let count = if aggregate.is_union { 1 } else { usize::MAX };
+                Some(
+                    aggregate
+                        .members
+                        .iter()
+                        .take(count)
+                        .map(|member| (member.offset, member.ty.clone()))
+                        .collect(),
+                )
+            }
+            _ => None,
+        }
+    }
+    pub fn load(&self) -> Option<Op> {
+        match self {
+            Type::Void | Type::Array(..) | Type::Struct(_) => None,
+            // Nothing to extend at full width, so 64-bit loads count as signed.
+            ty => Some(Op::Load {
+                size: ty.size() as u8,
+                signed: ty.is_signed() || ty.size() == 8,
+            }),
+        }
+    }
+    pub fn store(&self) -> Option<Op> {
+        if !self.is_scalar() {
+            return None;
+        }
+        Some(Op::Store {
+            size: self.size() as u8,
+        })
+    }
+}
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Void => write!(f, "void"),
+            Type::Char => write!(f, "char"),
+            Type::UChar => write!(f, "unsigned char"),
+            Type::Short => write!(f, "short"),
+            Type::UShort => write!(f, "unsigned short"),
+            Type::Int => write!(f, "int"),
+            Type::UInt => write!(f, "unsigned int"),
+            Type::Long => write!(f, "long"),
+            Type::ULong => write!(f, "unsigned long"),
+            Type::Pointer(ty) if ty.is_pointer() => write!(f, "{}*", ty),
+            Type::Pointer(ty) => write!(f, "{} *", ty),
+            Type::Array(ty, len) => write!(f, "{} [{}]", ty, len),
+            Type::Struct(aggregate) => write!(f, "{:?}", aggregate),
+        }
+    }
+}
@@ -1,8 +1,61 @@
 use num_enum::TryFromPrimitiveError;
-use std::{convert::TryInto, error::Error, fmt, mem::size_of};
+use std::{collections::VecDeque, convert::TryInto, error::Error, fmt, mem::size_of};
 type VMResult<T> = std::result::Result<T, VMError>;
+use super::clock::{Clock, Timer, TimerUnit};
+use super::display::{grey_palette, DisplayInfo, PixelFormat, Rect};
+use super::input::Event;
 use super::Instruction;
 use ansi_term::Colour::*;
+
+// The program is loaded at 0 and must end before the framebuffer. With int 0x0d it
+// can make its start, up to a given address, read-only; compiled programs do this
+// for their code and string literals. The framebuffer region is big enough for the
+// largest display mode, so the registers after it stay put when the mode changes,
+// but only the bytes the current mode uses are mapped; the rest fault like
+// addresses past the end of memory.
+pub const FRAMEBUFFER: usize = 0x8000;
+pub const FRAMEBUFFER_SIZE: usize = 0x40000;
+// Registers of the devices follow the framebuffer.
+const DEVICES: usize = FRAMEBUFFER + FRAMEBUFFER_SIZE;
+const DEVICES_SIZE: usize = 0x1000;
+// The width, height and pixel format of the display as 32-bit values. Read-only;
+// programs change the mode with int 0x03.
+pub const DISPLAY: usize = DEVICES;
+const DISPLAY_SIZE: usize = 12;
+// 256 colors as 32-bit 0xRRGGBB, which FORMAT_INDEXED8 pixels are looked up in
+// whenever the display is shown.
+pub const PALETTE: usize = DEVICES + 0x100;
+const PALETTE_SIZE: usize = 256 * 4;
+// Read-only 32-bit registers for the mouse position and buttons, the number of
+// queued events and the event int 0x0a took off the queue, then a byte per key
+// code that is 1 while the key is down.
+pub const INPUT: usize = DEVICES + 0x800;
+const MOUSE: usize = INPUT;
+const BUTTONS: usize = INPUT + 8;
+const EVENTS: usize = INPUT + 12;
+const EVENT: usize = INPUT + 16;
+const KEYS: usize = INPUT + 32;
+const INPUT_SIZE: usize = 32 + 256;
+// Events that come in while this many are waiting are dropped.
+const EVENT_QUEUE_SIZE: usize = 256;
+// Read-only 64-bit registers for the instructions run, the milliseconds since the
+// VM started and the milliseconds since 1970, then a 32-bit count of the times the
+// timer has gone off. They are brought up to date whenever they are read.
+pub const CLOCK: usize = DEVICES + 0xc00;
+const TIMER: usize = CLOCK + 24;
+const CLOCK_SIZE: usize = 28;
+// Programs cannot write the registers of these devices.
+const READ_ONLY: [(usize, usize); 3] = [
+    (DISPLAY, DISPLAY_SIZE),
+    (INPUT, INPUT_SIZE),
+    (CLOCK, CLOCK_SIZE),
+];
+// Function frames grow down from the top of memory and must stay above the devices.
+const STACK_LIMIT: usize = DEVICES + DEVICES_SIZE;
+pub const MEMORY_SIZE: usize = STACK_LIMIT + 0x7000;
+// Return addresses are kept apart from memory, so calls that need no frame are
+// limited to this many at a time.
+const CALL_DEPTH_LIMIT: usize = 0x10000;
 // Define our error types. These may be customized for our error handling cases.
 // Now we will be able to write our own errors, defer to an underlying error
 // implementation, or do something in between.
@@ -24,43 +77,224 @@ impl Error for VMError {
 }
 pub struct VM {
     pub memory: Vec<u8>,
-    pub framebuffer: Vec<u32>,
     pub stack: Vec<i64>,
     pub paused: bool,
+    // Set by the program when it exits; 0 if it halted instead.
+    pub exit_code: i64,
+    // Instructions executed so far.
+    pub steps: u64,
+    // Frames the program has marked as finished with int 0x02 or 0x0b.
+    pub frames: u64,
+    // Set by int 0x0b for whoever runs the VM to wait for the next frame.
+    pub vsync: bool,
     pub display: DisplayInfo,
+    // The event registers of key and button events the program has yet to read.
+    events: VecDeque<[u32; 4]>,
+    clock: Clock,
+    // Set with int 0x0c.
+    timer: Option<Timer>,
+    // Stores below this address fault. Raised with int 0x0d.
+    protected: usize,
     pub call_stack: Vec<StackFrame>,
     pc: usize,
+    fp: usize,
+    code_size: usize,
 }
 pub struct StackFrame {
     pub return_addr: usize,
+    pub frame_pointer: usize,
 }
 impl StackFrame {
-    pub fn new(return_addr: usize) -> StackFrame {
-        StackFrame { return_addr }
+    pub fn new(return_addr: usize, frame_pointer: usize) -> StackFrame {
+        StackFrame {
+            return_addr,
+            frame_pointer,
+        }
     }
 }
-pub struct DisplayInfo {
-    pub width: usize,
-    pub height: usize,
-}
 
 impl VM {
-    pub fn new(code: Vec<u8>, framebuffer: Vec<u32>, display: DisplayInfo) -> VM {
-        let len = code.len();
-        let mem = [code, Vec::with_capacity(65355 - len)].concat();
-        VM {
+    pub fn new(code: Vec<u8>) -> VM {
+        let code_size = code.len();
+        let mut mem = code;
+        if mem.len() < MEMORY_SIZE {
+            mem.resize(MEMORY_SIZE, 0);
+        }
+        let mut vm = VM {
             memory: mem,
-            framebuffer,
             stack: Vec::new(),
             paused: false,
-            display,
+            exit_code: 0,
+            steps: 0,
+            frames: 0,
+            vsync: false,
+            display: DisplayInfo::default(),
+            events: VecDeque::new(),
+            clock: Clock::real(),
+            timer: None,
+            protected: 0,
             call_stack: Vec::new(),
             pc: 0,
+            fp: MEMORY_SIZE,
+            code_size,
+        };
+        vm.set_display(DisplayInfo::default()).unwrap();
+        for (index, color) in grey_palette().into_iter().enumerate() {
+            vm.set_palette(index, color);
+        }
+        vm
+    }
+    pub fn set_palette(&mut self, index: usize, color: u32) {
+        let at = PALETTE + index * 4;
+        self.memory[at..at + 4].copy_from_slice(&color.to_le_bytes());
+    }
+    fn palette(&self) -> Vec<u32> {
+        self.memory[PALETTE..PALETTE + PALETTE_SIZE]
+            .chunks_exact(4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()) & 0xffffff)
+            .collect()
+    }
+    // Switches the display mode and clears the screen.
+    pub fn set_display(&mut self, display: DisplayInfo) -> VMResult<()> {
+        if display.width == 0 || display.height == 0 || display.size() > FRAMEBUFFER_SIZE {
+            return Err(VMError {
+                message: format!(
+                    "Unsupported display mode {}x{} {:?}",
+                    display.width, display.height, display.format
+                ),
+                address: self.pc,
+            });
+        }
+        self.display = display;
+        self.memory[FRAMEBUFFER..FRAMEBUFFER + FRAMEBUFFER_SIZE].fill(0);
+        let registers = [
+            display.width as u32,
+            display.height as u32,
+            display.format.code(),
+        ];
+        for (i, value) in registers.iter().enumerate() {
+            self.set_register(DISPLAY + i * 4, *value);
+        }
+        Ok(())
+    }
+    fn set_register(&mut self, at: usize, value: u32) {
+        self.memory[at..at + 4].copy_from_slice(&value.to_le_bytes());
+    }
+    fn register(&self, at: usize) -> u32 {
+        u32::from_le_bytes(self.memory[at..at + 4].try_into().unwrap())
+    }
+    // Updates the input registers, and queues key and button changes for int 0x0a.
+    pub fn input(&mut self, event: Event) {
+        let (kind, code) = match event {
+            Event::Move { x, y } => {
+                self.set_register(MOUSE, x as u32);
+                self.set_register(MOUSE + 4, y as u32);
+                return;
+            }
+            Event::Key { code, down } => {
+                self.memory[KEYS + code as usize] = down as u8;
+                (if down { 1 } else { 2 }, code)
+            }
+            Event::Button { button, down } => {
+                let buttons = self.register(BUTTONS) & !(1 << button);
+                self.set_register(BUTTONS, buttons | (down as u32) << button);
+                (if down { 3 } else { 4 }, button)
+            }
+        };
+        if self.events.len() < EVENT_QUEUE_SIZE {
+            let (x, y) = (self.register(MOUSE), self.register(MOUSE + 4));
+            self.events.push_back([kind, code as u32, x, y]);
+            self.set_register(EVENTS, self.events.len() as u32);
+        }
+    }
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+    // The time in `unit` since the VM started.
+    fn now(&self, unit: TimerUnit) -> u64 {
+        match unit {
+            TimerUnit::Millis => self.clock.millis(self.steps),
+            TimerUnit::Instructions => self.steps,
+        }
+    }
+    fn update_clock(&mut self) {
+        let registers = [
+            self.steps,
+            self.clock.millis(self.steps),
+            self.clock.time(self.steps),
+        ];
+        for (i, value) in registers.iter().enumerate() {
+            self.memory[CLOCK + i * 8..CLOCK + i * 8 + 8].copy_from_slice(&value.to_le_bytes());
+        }
+        let count = self
+            .timer
+            .map_or(0, |timer| timer.count(self.now(timer.unit)));
+        self.set_register(TIMER, count as u32);
+    }
+    // The pixels on display as 0xRRGGBB.
+    pub fn framebuffer(&self) -> Vec<u32> {
+        let framebuffer = &self.memory[FRAMEBUFFER..FRAMEBUFFER + self.display.size()];
+        self.display.render(framebuffer, &self.palette())
+    }
+    // The arguments of an interrupt, in the order they were pushed.
+    fn pop_args<const N: usize>(&mut self) -> [i64; N] {
+        let mut args = [0; N];
+        for arg in args.iter_mut().rev() {
+            *arg = self.stack.pop().expect("interrupt argument not on stack");
+        }
+        args
+    }
+    fn framebuffer_mut(&mut self) -> &mut [u8] {
+        &mut self.memory[FRAMEBUFFER..FRAMEBUFFER + self.display.size()]
+    }
+    fn put_pixel(&mut self, x: usize, y: usize, color: i64) -> VMResult<()> {
+        if x >= self.display.width || y >= self.display.height {
+            return Err(VMError {
+                message: "Pixel out of bounds".to_string(),
+                address: self.pc,
+            });
+        }
+        let display = self.display;
+        display.put(self.framebuffer_mut(), x, y, color);
+        Ok(())
+    }
+    // Copies a sprite from memory; only the part on the display is drawn.
+    fn blit(&mut self, sprite: i64, rect: Rect, key: i64) -> VMResult<()> {
+        let display = self.display;
+        let pixels = rect.width.max(0) as u128 * rect.height.max(0) as u128;
+        let size = pixels * display.sprite_pixel_size() as u128;
+        let end = sprite as u128 + size;
+        if sprite < 0
+            || end > self.memory.len() as u128
+            || !self.mapped(sprite as usize, end as usize)
+        {
+            return Err(VMError {
+                message: "Sprite out of bounds".to_string(),
+                address: self.pc,
+            });
+        }
+        let start = sprite as usize;
+        // The sprite may be in the framebuffer itself.
+        let sprite = self.memory[start..start + size as usize].to_vec();
+        display.blit(self.framebuffer_mut(), &sprite, rect, key);
+        Ok(())
+    }
+    // The bytes of the NUL terminated string at `address`, without the NUL.
+    fn string(&self, address: i64) -> VMResult<&[u8]> {
+        let start: usize = address.try_into().unwrap_or(usize::MAX);
+        let rest = self.memory.get(start..).unwrap_or_default();
+        match rest.iter().position(|&b| b == 0) {
+            Some(end) if self.mapped(start, start + end + 1) => Ok(&rest[..end]),
+            _ => Err(VMError {
+                message: "String out of bounds".to_string(),
+                address: self.pc,
+            }),
         }
     }
     pub fn step(self: &mut VM) -> VMResult<()> {
         let vm = self;
         vm.execute()?;
+        vm.steps += 1;
         Ok(())
     }
     pub fn stop(self: &mut VM) {
@@ -68,7 +302,7 @@ impl VM {
     }
 
     fn execute(self: &mut VM) -> VMResult<()> {
-        let mut vm = self;
+        let vm = self;
         if vm.pc >= vm.memory.len() {
             return Err(VMError {
                 message: "PC out of bounds".to_string(),
@@ -91,44 +325,58 @@ impl VM {
                         ));
                         vm.pc += size_of::<i64>();
                     }
+                    // Arithmetic wraps around, like unsigned arithmetic in C.
                     Instruction::Add => {
                         let val1 = vm.stack.pop().unwrap();
                         let val2 = vm.stack.pop().unwrap();
-                        vm.stack.push(val1 + val2);
+                        vm.stack.push(val1.wrapping_add(val2));
                     }
                     Instruction::Mul => {
                         let val2 = vm.stack.pop().unwrap();
                         let val1 = vm.stack.pop().unwrap();
 
-                        vm.stack.push(val1 * val2);
+                        vm.stack.push(val1.wrapping_mul(val2));
                     }
                     Instruction::Sub => {
                         let val2 = vm.stack.pop().unwrap();
                         let val1 = vm.stack.pop().unwrap();
 
-                        vm.stack.push(val2 - val1);
+                        vm.stack.push(val2.wrapping_sub(val1));
                     }
                     Instruction::Div => {
                         let val2 = vm.stack.pop().unwrap();
                         let val1 = vm.stack.pop().unwrap();
-                        vm.stack.push(val2 / val1);
+                        if val1 == 0 {
+                            return Err(VMError {
+                                message: "Division by zero".to_string(),
+                                address: vm.pc,
+                            });
+                        }
+                        // Only i64::MIN / -1 overflows.
+                        let Some(val) = val2.checked_div(val1) else {
+                            return Err(VMError {
+                                message: "Division overflow".to_string(),
+                                address: vm.pc,
+                            });
+                        };
+                        vm.stack.push(val);
                     }
                     Instruction::Jump => {
                         let dest = vm.stack.pop().unwrap();
-                        vm.pc = (dest - 1).clamp(0, i64::MAX) as usize;
+                        vm.pc = dest.clamp(0, i64::MAX) as usize;
                     }
                     Instruction::Pop => {
                         vm.stack.pop();
                     }
                     Instruction::LoadU8 => {
-                        let addr = vm.stack.pop().unwrap();
-                        let val = vm.memory[addr as usize];
+                        let addr = vm.stack.pop().unwrap() as usize;
+                        let val = vm.load(addr, 1)?;
                         vm.stack.push(val as i64);
                     }
                     Instruction::StoreU8 => {
-                        let addr = vm.stack.pop().unwrap();
+                        let addr = vm.stack.pop().unwrap() as usize;
                         let val = vm.stack.pop().unwrap();
-                        vm.set_memory(addr as usize, val as u8);
+                        vm.store(addr, 1, val)?;
                     }
 
                     Instruction::Swap => {
@@ -153,14 +401,126 @@ impl VM {
                         vm.pc += size_of::<u8>();
                         match interrupt {
                             0x0 => {
-                                vm.framebuffer[vm.stack.pop().unwrap() as usize] =
-                                    vm.stack.pop().unwrap() as u32;
+                                let index = vm.stack.pop().expect("index not on stack") as usize;
+                                let color = vm.stack.pop().expect("color not on stack");
+                                let width = vm.display.width;
+                                // An index past the last row is out of bounds too.
+                                vm.put_pixel(index % width, index / width, color)?;
                             }
                             0x1 => {
                                 let color = vm.stack.pop().expect("color not on stack");
                                 let y = vm.stack.pop().expect("y position not on stack") as usize;
                                 let x = vm.stack.pop().expect("x position not on stack") as usize;
-                                vm.framebuffer[(y * vm.display.width + x) as usize] = color as u32;
+                                vm.put_pixel(x, y, color)?;
+                            }
+                            // The program has finished drawing a frame.
+                            0x2 => vm.frames += 1,
+                            0x3 => {
+                                let format = vm.stack.pop().expect("format not on stack");
+                                let height = vm.stack.pop().expect("height not on stack");
+                                let width = vm.stack.pop().expect("width not on stack");
+                                let Some(format) = PixelFormat::from_code(format) else {
+                                    return Err(VMError {
+                                        message: format!("Unknown pixel format {}", format),
+                                        address: vm.pc,
+                                    });
+                                };
+                                vm.set_display(DisplayInfo {
+                                    width: width.clamp(0, i32::MAX as i64) as usize,
+                                    height: height.clamp(0, i32::MAX as i64) as usize,
+                                    format,
+                                })?;
+                            }
+                            0x4 => {
+                                let color = vm.stack.pop().expect("color not on stack");
+                                let index = vm.stack.pop().expect("index not on stack");
+                                if !(0..256).contains(&index) {
+                                    return Err(VMError {
+                                        message: "Palette index out of bounds".to_string(),
+                                        address: vm.pc,
+                                    });
+                                }
+                                vm.set_palette(index as usize, color as u32);
+                            }
+                            0x5 => {
+                                let [color] = vm.pop_args();
+                                let display = vm.display;
+                                let rect = Rect {
+                                    x: 0,
+                                    y: 0,
+                                    width: display.width as i64,
+                                    height: display.height as i64,
+                                };
+                                display.fill(vm.framebuffer_mut(), rect, color);
+                            }
+                            0x6 => {
+                                let [x, y, width, height, color] = vm.pop_args();
+                                let display = vm.display;
+                                let rect = Rect {
+                                    x,
+                                    y,
+                                    width,
+                                    height,
+                                };
+                                display.fill(vm.framebuffer_mut(), rect, color);
+                            }
+                            0x7 => {
+                                let [x0, y0, x1, y1, color] = vm.pop_args();
+                                let display = vm.display;
+                                display.line(vm.framebuffer_mut(), x0, y0, x1, y1, color);
+                            }
+                            0x8 => {
+                                let [sprite, x, y, width, height, key] = vm.pop_args();
+                                let rect = Rect {
+                                    x,
+                                    y,
+                                    width,
+                                    height,
+                                };
+                                vm.blit(sprite, rect, key)?;
+                            }
+                            0x9 => {
+                                let [text, x, y, color] = vm.pop_args();
+                                let text = vm.string(text)?.to_vec();
+                                let display = vm.display;
+                                display.text(vm.framebuffer_mut(), &text, x, y, color);
+                            }
+                            // An empty queue leaves the registers zero, which is EVENT_NONE.
+                            0xa => {
+                                let event = vm.events.pop_front().unwrap_or_default();
+                                for (i, value) in event.iter().enumerate() {
+                                    vm.set_register(EVENT + i * 4, *value);
+                                }
+                                vm.set_register(EVENTS, vm.events.len() as u32);
+                            }
+                            0xb => {
+                                vm.frames += 1;
+                                vm.vsync = true;
+                            }
+                            0xc => {
+                                let [period, unit] = vm.pop_args();
+                                let Some(unit) = TimerUnit::from_code(unit) else {
+                                    return Err(VMError {
+                                        message: format!("Unknown timer unit {}", unit),
+                                        address: vm.pc,
+                                    });
+                                };
+                                vm.timer = (period > 0).then(|| Timer {
+                                    period: period as u64,
+                                    unit,
+                                    start: vm.now(unit),
+                                });
+                            }
+                            0xd => {
+                                let [end] = vm.pop_args();
+                                if !(0..=FRAMEBUFFER as i64).contains(&end) {
+                                    return Err(VMError {
+                                        message: "Can only protect the program".to_string(),
+                                        address: vm.pc,
+                                    });
+                                }
+                                // What is read-only stays that way.
+                                vm.protected = vm.protected.max(end as usize);
                             }
                             _ => {
                                 return Err(VMError {
@@ -172,13 +532,139 @@ impl VM {
                     }
                     Instruction::Call => {
                         let dest = vm.stack.pop().expect("Address on the stack to call");
-                        vm.call_stack.push(StackFrame::new(vm.pc));
+                        if vm.call_stack.len() >= CALL_DEPTH_LIMIT {
+                            return Err(VMError {
+                                message: "Stack overflow".to_string(),
+                                address: vm.pc,
+                            });
+                        }
+                        vm.call_stack.push(StackFrame::new(vm.pc, vm.fp));
                         vm.pc = (dest).clamp(0, i64::MAX).try_into().unwrap();
                     }
                     Instruction::Ret => {
-                        vm.call_stack
+                        let frame = vm
+                            .call_stack
                             .pop()
                             .expect("Cannot return when call stack is empty");
+                        vm.pc = frame.return_addr;
+                        vm.fp = frame.frame_pointer;
+                    }
+                    Instruction::JumpZero => {
+                        let dest = vm.stack.pop().unwrap();
+                        let cond = vm.stack.pop().unwrap();
+                        if cond == 0 {
+                            vm.pc = dest.clamp(0, i64::MAX) as usize;
+                            return Ok(());
+                        }
+                    }
+                    Instruction::Eq => {
+                        let val2 = vm.stack.pop().unwrap();
+                        let val1 = vm.stack.pop().unwrap();
+                        vm.stack.push((val1 == val2) as i64);
+                    }
+                    Instruction::Lt => {
+                        let val2 = vm.stack.pop().unwrap();
+                        let val1 = vm.stack.pop().unwrap();
+                        vm.stack.push((val1 < val2) as i64);
+                    }
+                    Instruction::LoadI64 => {
+                        let addr = vm.stack.pop().unwrap() as usize;
+                        let val = vm.load(addr, 8)?;
+                        vm.stack.push(val as i64);
+                    }
+                    Instruction::StoreI64 => {
+                        let addr = vm.stack.pop().unwrap() as usize;
+                        let val = vm.stack.pop().unwrap();
+                        vm.store(addr, 8, val)?;
+                    }
+                    Instruction::Enter => {
+                        let size =
+                            i64::from_le_bytes(vm.memory[vm.pc + 1..vm.pc + 9].try_into().unwrap());
+                        vm.pc += size_of::<i64>();
+                        if size < 0 || vm.fp < STACK_LIMIT + size as usize {
+                            return Err(VMError {
+                                message: "Stack overflow".to_string(),
+                                address: vm.pc,
+                            });
+                        }
+                        vm.fp -= size as usize;
+                    }
+                    Instruction::FramePointer => {
+                        vm.stack.push(vm.fp as i64);
+                    }
+                    Instruction::LoadI8 => {
+                        let addr = vm.stack.pop().unwrap() as usize;
+                        let val = vm.load(addr, 1)?;
+                        vm.stack.push(val as i8 as i64);
+                    }
+                    Instruction::LoadU16 => {
+                        let addr = vm.stack.pop().unwrap() as usize;
+                        let val = vm.load(addr, 2)?;
+                        vm.stack.push(val as u16 as i64);
+                    }
+                    Instruction::LoadI16 => {
+                        let addr = vm.stack.pop().unwrap() as usize;
+                        let val = vm.load(addr, 2)?;
+                        vm.stack.push(val as i16 as i64);
+                    }
+                    Instruction::LoadU32 => {
+                        let addr = vm.stack.pop().unwrap() as usize;
+                        let val = vm.load(addr, 4)?;
+                        vm.stack.push(val as u32 as i64);
+                    }
+                    Instruction::LoadI32 => {
+                        let addr = vm.stack.pop().unwrap() as usize;
+                        let val = vm.load(addr, 4)?;
+                        vm.stack.push(val as i32 as i64);
+                    }
+                    Instruction::StoreU16 => {
+                        let addr = vm.stack.pop().unwrap() as usize;
+                        let val = vm.stack.pop().unwrap();
+                        vm.store(addr, 2, val)?;
+                    }
+                    Instruction::StoreU32 => {
+                        let addr = vm.stack.pop().unwrap() as usize;
+                        let val = vm.stack.pop().unwrap();
+                        vm.store(addr, 4, val)?;
+                    }
+                    Instruction::SignExtend | Instruction::ZeroExtend => {
+                        let width = vm.memory[vm.pc + 1] as u32;
+                        vm.pc += size_of::<u8>();
+                        if !matches!(width, 1 | 2 | 4 | 8) {
+                            return Err(VMError {
+                                message: "Invalid extension width".to_string(),
+                                address: vm.pc,
+                            });
+                        }
+                        let shift = 64 - width * 8;
+                        let val = vm.stack.pop().unwrap();
+                        if instr == Instruction::SignExtend {
+                            vm.stack.push(val.wrapping_shl(shift).wrapping_shr(shift));
+                        } else {
+                            let val = (val as u64).wrapping_shl(shift).wrapping_shr(shift);
+                            vm.stack.push(val as i64);
+                        }
+                    }
+                    Instruction::LtU => {
+                        let val2 = vm.stack.pop().unwrap() as u64;
+                        let val1 = vm.stack.pop().unwrap() as u64;
+                        vm.stack.push((val1 < val2) as i64);
+                    }
+                    Instruction::Exit => {
+                        vm.exit_code = vm.stack.pop().unwrap();
+                        vm.stop();
+                        return Ok(());
+                    }
+                    Instruction::DivU => {
+                        let val2 = vm.stack.pop().unwrap() as u64;
+                        let val1 = vm.stack.pop().unwrap() as u64;
+                        if val1 == 0 {
+                            return Err(VMError {
+                                message: "Division by zero".to_string(),
+                                address: vm.pc,
+                            });
+                        }
+                        vm.stack.push((val2 / val1) as i64);
                     }
                 }
                 if instr != Instruction::Jump && instr != Instruction::Call {
@@ -192,11 +678,50 @@ impl VM {
             }),
         }
     }
+    // Whether start..end is within memory and misses the unused part of the framebuffer.
+    fn mapped(&self, start: usize, end: usize) -> bool {
+        let unused = FRAMEBUFFER + self.display.size()..FRAMEBUFFER + FRAMEBUFFER_SIZE;
+        end <= self.memory.len() && (end <= unused.start || start >= unused.end)
+    }
+    fn check_address(self: &VM, addr: usize, size: usize) -> VMResult<()> {
+        if addr
+            .checked_add(size)
+            .is_none_or(|end| !self.mapped(addr, end))
+        {
+            return Err(VMError {
+                message: "Memory access out of bounds".to_string(),
+                address: self.pc,
+            });
+        }
+        Ok(())
+    }
+    fn load(self: &mut VM, addr: usize, size: usize) -> VMResult<u64> {
+        self.check_address(addr, size)?;
+        if addr < CLOCK + CLOCK_SIZE && addr + size > CLOCK {
+            self.update_clock();
+        }
+        let mut bytes = [0; 8];
+        bytes[..size].copy_from_slice(&self.memory[addr..addr + size]);
+        Ok(u64::from_le_bytes(bytes))
+    }
+    fn store(self: &mut VM, addr: usize, size: usize, val: i64) -> VMResult<()> {
+        self.check_address(addr, size)?;
+        if addr < self.protected {
+            return Err(VMError {
+                message: "Write to read-only memory".to_string(),
+                address: self.pc,
+            });
+        }
+        for (i, byte) in val.to_le_bytes()[..size].iter().enumerate() {
+            self.set_memory(addr + i, *byte);
+        }
+        Ok(())
+    }
     pub fn set_memory(self: &mut VM, addr: usize, val: u8) {
-        if addr >= 0x8000 && addr < 0x9000 {
-            self.framebuffer[addr - 0x8000] =
-                val as u32 + ((val as u32) << 8) + ((val as u32) << 16) + ((val as u32) << 24);
-        } else {
+        if !READ_ONLY
+            .iter()
+            .any(|&(start, size)| (start..start + size).contains(&addr))
+        {
             self.memory[addr] = val;
         }
     }
@@ -210,10 +735,14 @@ impl fmt::Debug for VM {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(f, "VM")?;
         writeln!(f, "  pc: {}", self.pc)?;
+        writeln!(f, "  fp: {:04x}", self.fp)?;
         writeln!(f, "  stack: {:?}", self.stack)?;
         writeln!(f, "  paused: {}", self.paused)?;
+        writeln!(f, "  exit code: {}", self.exit_code)?;
+        writeln!(f, "  steps: {}", self.steps)?;
+        writeln!(f, "  frames: {}", self.frames)?;
         writeln!(f, "Debugger")?;
-        for i in 0..self.memory.len() {
+        for i in 0..self.code_size {
             if self.pc == i {
                 write!(f, "{}  →  ", Red.paint(format!("0x{:04x}", i)))?;
             } else {
@@ -227,12 +756,7 @@ impl fmt::Debug for VM {
                 }
                 Ok(x) => {
                     let comment = get_instr_comment(&x);
-                    writeln!(
-                        f,
-                        "{:?} {}",
-                        x,
-                        Green.paint("# ".to_string() + &comment).to_string()
-                    )?;
+                    writeln!(f, "{:?} {}", x, Green.paint("# ".to_string() + &comment))?;
                 }
             }
         }
@@ -248,7 +772,63 @@ fn get_instr_comment(instr: &Instruction) -> String {
         Instruction::Sub => "Subtract two numbers popped off the stack",
         Instruction::Div => "Divide two numbers popped off the stack",
         Instruction::Halt => "Halt execution",
+        Instruction::Exit => "Halt with the exit status popped off the stack",
+        Instruction::Call => "Call the address popped off the stack",
+        Instruction::Ret => "Return to the caller",
+        Instruction::JumpZero => "Jump if the second value popped off the stack is zero",
+        Instruction::Enter => "Allocate a stack frame",
         _ => "No comment",
     }
     .to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    // The message of the fault that stops the program.
+    fn fault(asm: &str) -> String {
+        let mut vm = VM::new(assemble(asm.to_string()));
+        while !vm.paused {
+            if let Err(err) = vm.step() {
+                return err.message;
+            }
+        }
+        panic!("program halted without a fault");
+    }
+
+    #[test]
+    fn division_faults() {
+        assert_eq!(fault("push 0\npush 5\ndiv\nhlt"), "Division by zero");
+        let overflow = "push -1\npush -9223372036854775808\ndiv\nhlt";
+        assert_eq!(fault(overflow), "Division overflow");
+    }
+
+    #[test]
+    fn stores_out_of_bounds_fault() {
+        let out_of_bounds = "Memory access out of bounds";
+        assert_eq!(fault("push 1\npush -1\nstoreu8\nhlt"), out_of_bounds);
+        assert_eq!(fault("push 1\npush -8\nstorei64\nhlt"), out_of_bounds);
+        assert_eq!(fault("push -8\nloadi64\nhlt"), out_of_bounds);
+    }
+
+    #[test]
+    fn framebuffer_is_sized_to_the_mode() {
+        let out_of_bounds = "Memory access out of bounds";
+        // 64 x 64 indexed8 uses 0x1000 bytes.
+        let past_end = "push 1\npush 36863\nstoreu8\npush 1\npush 36864\nstoreu8\nhlt";
+        assert_eq!(fault(past_end), out_of_bounds);
+        assert_eq!(fault("push 1\npush 36862\nstoreu32\nhlt"), out_of_bounds);
+        assert_eq!(fault("push 36864\nloadu8\nhlt"), out_of_bounds);
+        let text = "push 36864\npush 0\npush 0\npush 1\nint 9\nhlt";
+        assert_eq!(fault(text), "String out of bounds");
+        // 128 x 128 ARGB32 uses 0x10000.
+        let larger = "push 128\npush 128\npush 0\nint 3\npush 1\npush 36864\nstoreu8\nhlt";
+        let mut vm = VM::new(assemble(larger.to_string()));
+        while !vm.paused {
+            vm.step().unwrap();
+        }
+        assert_eq!(vm.memory[36864], 1);
+    }
+}
@@ -0,0 +1,22 @@
+/* Draws a few shapes, then a pixel wherever the left button is pressed until
+   space is pressed. */
+#include "badvm.h"
+
+int main(void) {
+    set_display(24, 16, FORMAT_ARGB32);
+    clear_screen(BLUE);
+    fill_rect(1, 1, 6, 4, RED);
+    draw_line(0, 15, 23, 6, GREEN);
+    draw_text("HI", 10, 1, WHITE);
+    while (1) {
+        int event = next_event();
+        if (event == EVENT_BUTTON_DOWN) {
+            put_pixel(EVENT_X, EVENT_Y, rgb(255, 255, 0));
+        }
+        if (event == EVENT_KEY_DOWN) {
+            if (EVENT_CODE == KEY_SPACE) {
+                return 0;
+            }
+        }
+    }
+}
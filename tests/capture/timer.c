@@ -0,0 +1,19 @@
+/* Marks each of the first eight times the timer goes off with a pixel as high
+   as the milliseconds the program has run, then writes how long that took. */
+#include "badvm.h"
+#include "stdlib.h"
+
+int main(void) {
+    char text[16];
+    int ticks = 0;
+    set_display(32, 16, FORMAT_INDEXED8);
+    set_timer(5, TIMER_MILLIS);
+    while (ticks < 8) {
+        if (TIMER != ticks) {
+            ticks = TIMER;
+            put_pixel(ticks, 15 - (int)(MILLIS / 5), 255);
+        }
+    }
+    draw_text(itoa((int)MILLIS, text, 10), 12, 1, 200);
+    return 0;
+}